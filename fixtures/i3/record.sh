@@ -0,0 +1,56 @@
+#!/bin/sh
+# Record the get_tree fixtures used by the tests in src/wm_i3.rs from a running i3.
+#
+# Run this in a fresh i3 session on a 1920x1080 output with the default config, eg in
+# `Xephyr :1 -screen 1920x1080` with `DISPLAY=:1 i3`, and xterm installed. It lays out
+# placeholder windows titled like the windows the tests look for and dumps the tree:
+#
+# Workspace 1: a tabbed container (vim, a vertical split of Mozilla Firefox and htop) next to a
+# stacked container (Signal, a tabbed container of notes.md and Zathura) and a floating
+# pavucontrol.
+# Workspace 2: a tabbed container of mutt and a horizontal split of Telegram and mpv in the
+# background tab.
+set -eu
+
+cd "$(dirname "$0")"
+
+cmd() {
+    i3-msg -q "$@"
+}
+
+# Open an xterm titled $1 with the window class $2 and wait until i3 manages it.
+spawn() {
+    cmd "exec xterm -T '$1' -class '${2:-XTerm}' -e sleep infinity"
+    until i3-msg -t get_tree | grep -q "\"title\":\"$1\""; do
+        sleep 0.1
+    done
+    cmd "[title=\"^$1\$\"] focus"
+}
+
+cmd 'workspace 1'
+spawn vim
+cmd 'split v, layout tabbed'
+spawn 'Mozilla Firefox'
+cmd 'split v'
+spawn htop
+cmd 'focus parent, focus parent'
+spawn Signal
+cmd 'split v, layout stacking'
+spawn notes.md
+cmd 'split h, layout tabbed'
+spawn Zathura
+spawn pavucontrol Pavucontrol
+cmd 'floating enable, resize set 400 300, move position 600 300'
+cmd '[title="^notes.md$"] focus'
+cmd '[title="^htop$"] focus'
+
+cmd 'workspace 2'
+spawn mutt
+cmd 'split v, layout tabbed'
+spawn Telegram
+cmd 'split h'
+spawn mpv
+cmd '[title="^mutt$"] focus'
+
+i3-msg -t get_tree | python3 -m json.tool --indent 2 > nested.json
+echo "Recorded $(pwd)/nested.json, the placeholder windows can be closed now."
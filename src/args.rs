@@ -1,9 +1,11 @@
-use anyhow::{Context, Result};
-use clap::{ArgEnum, Parser};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgEnum, ArgMatches, FromArgMatches, IntoApp, Parser};
 use css_color_parser::Color as CssColor;
-use font_loader::system_fonts;
 use log::{info, warn};
+use std::collections::HashSet;
+use std::iter;
 
+use crate::theme::{self, Theme};
 use crate::utils;
 
 #[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -20,45 +22,95 @@ pub enum VerticalAlign {
     Bottom,
 }
 
-/// Load a system font.
-fn load_font(font_family: &str) -> Result<Vec<u8>> {
-    let mut font_family_property = system_fonts::FontPropertyBuilder::new()
-        .family(font_family)
-        .build();
-    let info = system_fonts::query_specific(&mut font_family_property);
-    info!("Returned effective font is: {:?}", info);
-    let (loaded_font, _) =
-        if let Some((loaded_font, index)) = system_fonts::get(&font_family_property) {
-            (loaded_font, index)
-        } else {
-            warn!("Family not found, falling back to first Monospace font");
-            let mut font_monospace_property =
-                system_fonts::FontPropertyBuilder::new().monospace().build();
-            let sysfonts = system_fonts::query_specific(&mut font_monospace_property);
-            warn!("Falling back to font '{font}'", font = sysfonts[0]);
-            let (loaded_font, index) = system_fonts::get(&font_monospace_property)
-                .context("Couldn't find suitable font")?;
-            (loaded_font, index)
-        };
-    Ok(loaded_font)
+/// Which part of a window hints are aligned in.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Anchor {
+    Window,
+    Titlebar,
+    Content,
+}
+
+/// Which output to restrict hints to.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputSelection {
+    Cursor,
+    Focused,
+    Pick,
+}
+
+/// What happens to the window that gets selected.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Focus,
+    Swap,
+    Close,
+}
+
+impl Action {
+    /// The action after this one when cycling through actions.
+    pub fn next(self) -> Action {
+        match self {
+            Action::Focus => Action::Swap,
+            Action::Swap => Action::Close,
+            Action::Close => Action::Focus,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Focus => "focus",
+            Action::Swap => "swap",
+            Action::Close => "close",
+        }
+    }
 }
 
+/// Size in pixels at 96 DPI of fonts given without one, the same as the default font.
+const DEFAULT_FONT_SIZE: f64 = 72.0;
+
 /// Generate a valid `FontConfig` from `f`.
-/// `f` is expected to be in format `Mono:72`.
-fn parse_truetype_font(f: &str) -> Result<FontConfig> {
-    let v: Vec<_> = f.split(':').collect();
-    let (family, size) = (
-        v.get(0).context("Wrong font format")?,
-        v.get(1).context("Wrong font format")?,
-    );
+/// `f` is either a Pango font description such as `Iosevka Bold 40` (size in points) or in the
+/// format `Mono:72` (size in pixels at 96 DPI). Both get scaled to the DPI of the output later on.
+/// Descriptions without a size get `DEFAULT_FONT_SIZE`.
+fn parse_font(f: &str) -> Result<FontConfig> {
+    let mut description = match f.rsplit_once(':') {
+        Some((family, size)) => {
+            let size = size.parse::<f64>().context("Couldn't parse font size")?;
+            let mut description = pango::FontDescription::new();
+            description.set_family(family);
+            description.set_absolute_size(size * f64::from(pango::SCALE));
+            description
+        }
+        None => pango::FontDescription::from_string(f),
+    };
+    if description.size() == 0 {
+        description.set_absolute_size(DEFAULT_FONT_SIZE * f64::from(pango::SCALE));
+    }
+    info!("Using font '{}'", description);
+    Ok(FontConfig { description })
+}
 
-    let loaded_font = load_font(family).context("Couldn't load font")?;
-    let font_config = FontConfig {
-        font_family: family.to_string(),
-        font_size: size.parse::<f64>().context("Couldn't parse font size")?,
-        loaded_font,
+/// Parse a font size given in pixels at 96 DPI (eg `40`), in exact pixels (eg `40px`) or relative
+/// to the height of the window (eg `5%`).
+fn parse_font_size(s: &str) -> Result<FontSize, String> {
+    let size = if let Some(percent) = s.strip_suffix('%') {
+        percent
+            .parse::<f64>()
+            .map(|percent| FontSize::WindowHeight(percent / 100.0))
+    } else if let Some(pixels) = s.strip_suffix("px") {
+        pixels.parse::<f64>().map(FontSize::Pixels)
+    } else {
+        s.parse::<f64>().map(FontSize::Scaled)
     };
-    Ok(font_config)
+    match size {
+        Ok(FontSize::Scaled(size) | FontSize::Pixels(size) | FontSize::WindowHeight(size))
+            if size <= 0.0 =>
+        {
+            Err(format!("Font size '{}' has to be positive", s))
+        }
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("Couldn't parse font size '{}'", s)),
+    }
 }
 
 /// Validate coordinates and parse offset.
@@ -79,6 +131,52 @@ fn parse_offset(c: &str) -> Result<Offset, String> {
     Ok(offset)
 }
 
+/// Parse a length given in pixels (eg `8` or `8px`) or relative to the font size (eg `0.5em`).
+fn parse_length(s: &str) -> Result<Length, String> {
+    let length = if let Some(em) = s.strip_suffix("em") {
+        em.parse::<f64>().map(Length::Em)
+    } else {
+        s.strip_suffix("px")
+            .unwrap_or(s)
+            .parse::<f64>()
+            .map(Length::Pixels)
+    };
+    length.map_err(|_| format!("Couldn't parse length '{}'", s))
+}
+
+/// Parse padding given as `x,y` or as a single value for both.
+fn parse_padding(p: &str) -> Result<Padding, String> {
+    let v: Vec<_> = p.split(',').collect();
+    match v[..] {
+        [both] => {
+            let length = parse_length(both)?;
+            Ok(Padding {
+                x: length,
+                y: length,
+            })
+        }
+        [x, y] => Ok(Padding {
+            x: parse_length(x)?,
+            y: parse_length(y)?,
+        }),
+        _ => Err("Wrong padding format, expected x,y or a single value".to_string()),
+    }
+}
+
+/// Validate and parse a size given as `width,height`.
+fn parse_size(s: &str) -> Result<Size, String> {
+    let v: Vec<_> = s.split(',').collect();
+    let (width, height) = (
+        v.get(0).ok_or("Wrong size format, expected width,height")?,
+        v.get(1).ok_or("Wrong size format, expected width,height")?,
+    );
+    let size = Size {
+        width: width.parse::<u32>().map_err(|_| "Couldn't parse width")?,
+        height: height.parse::<u32>().map_err(|_| "Couldn't parse height")?,
+    };
+    Ok(size)
+}
+
 /// Parse a color into a tuple of floats.
 fn parse_color(color_str: &str) -> Result<(f64, f64, f64, f64), String> {
     let color = color_str
@@ -92,38 +190,268 @@ fn parse_color(color_str: &str) -> Result<(f64, f64, f64, f64), String> {
     ))
 }
 
+/// Parse hint keys given by their physical position, either as preset or as comma separated list
+/// of XKB key names such as `AC01,AC02`.
+fn parse_hint_keys(s: &str) -> Result<HintKeys, String> {
+    let names = match s {
+        // The positions of the default --chars on a QWERTY keyboard.
+        "default" => "AC02,AC01,AC03,AC04,AC07,AC08,AC09,AD03,AD02,AB03,AB07,AD10,AC05,AC06",
+        "homerow" => "AC01,AC02,AC03,AC04,AC07,AC08,AC09,AC10,AC05,AC06",
+        "numbers" => "AE01,AE02,AE03,AE04,AE05,AE06,AE07,AE08,AE09,AE10",
+        keys => keys,
+    };
+    let names: Vec<_> = names
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .collect();
+    if let Some(name) = names
+        .iter()
+        .find(|name| name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()))
+    {
+        return Err(format!("Invalid key name '{}'", name));
+    }
+    Ok(HintKeys { names })
+}
+
+/// Names of the physical keys used for hints.
+#[derive(Debug)]
+pub struct HintKeys {
+    pub names: Vec<String>,
+}
+
+/// Part of a label template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelPart {
+    Text(String),
+    Hint,
+    Title,
+    Class,
+}
+
+#[derive(Debug)]
+pub struct LabelTemplate {
+    pub parts: Vec<LabelPart>,
+}
+
+impl LabelTemplate {
+    /// Template to switch to when toggling the title display: just the hint for labels that show
+    /// window metadata and the hint followed by the title otherwise.
+    pub fn toggled(&self) -> LabelTemplate {
+        let shows_metadata = self
+            .parts
+            .iter()
+            .any(|part| matches!(part, LabelPart::Title | LabelPart::Class));
+        let parts = if shows_metadata {
+            vec![LabelPart::Hint]
+        } else {
+            vec![
+                LabelPart::Hint,
+                LabelPart::Text(" ".to_string()),
+                LabelPart::Title,
+            ]
+        };
+        LabelTemplate { parts }
+    }
+}
+
+/// Parse a label template such as `{hint} {class}: {title}`.
+fn parse_label(s: &str) -> Result<LabelTemplate, String> {
+    let mut parts = vec![];
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}').ok_or("Unclosed '{' in label")?;
+        if start > 0 {
+            parts.push(LabelPart::Text(rest[..start].to_string()));
+        }
+        parts.push(match &rest[start + 1..end] {
+            "hint" => LabelPart::Hint,
+            "title" => LabelPart::Title,
+            "class" => LabelPart::Class,
+            other => return Err(format!("Unknown label placeholder '{{{}}}'", other)),
+        });
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(LabelPart::Text(rest.to_string()));
+    }
+    if parts.iter().filter(|p| **p == LabelPart::Hint).count() != 1 {
+        return Err("Label has to contain {hint} exactly once".to_string());
+    }
+    Ok(LabelTemplate { parts })
+}
+
 #[derive(Debug)]
 pub struct Offset {
     pub x: i32,
     pub y: i32,
 }
 
+/// A length either in pixels or relative to the font size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Pixels(f64),
+    Em(f64),
+}
+
+impl Length {
+    /// Convert to pixels for a font that is `font_size` pixels large.
+    pub fn to_pixels(self, font_size: f64) -> f64 {
+        match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Em(em) => em * font_size,
+        }
+    }
+}
+
+/// A font size in pixels at 96 DPI that gets scaled to the DPI of the output, in exact pixels or
+/// as a fraction of the window height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FontSize {
+    Scaled(f64),
+    Pixels(f64),
+    WindowHeight(f64),
+}
+
 #[derive(Debug)]
+pub struct Padding {
+    pub x: Length,
+    pub y: Length,
+}
+
+#[derive(Debug)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone)]
 pub struct FontConfig {
-    pub font_family: String,
-    pub font_size: f64,
-    pub loaded_font: Vec<u8>,
+    pub description: pango::FontDescription,
+}
+
+impl FontConfig {
+    /// Return a copy of this font with its size multiplied by `factor`.
+    pub fn scaled(&self, factor: f64) -> FontConfig {
+        let mut description = self.description.clone();
+        let size = f64::from(description.size()) * factor;
+        if description.is_size_absolute() {
+            description.set_absolute_size(size);
+        } else {
+            description.set_size(size.round() as i32);
+        }
+        FontConfig { description }
+    }
+
+    /// Return a copy of this font that is `size` pixels large.
+    pub fn with_pixel_size(&self, size: f64) -> FontConfig {
+        let mut description = self.description.clone();
+        description.set_absolute_size(size * f64::from(pango::SCALE));
+        FontConfig { description }
+    }
+
+    /// Size of this font in pixels, assuming the default resolution of 96 DPI for sizes in points.
+    pub fn pixel_size(&self) -> f64 {
+        let size = f64::from(self.description.size()) / f64::from(pango::SCALE);
+        if self.description.is_size_absolute() {
+            size
+        } else {
+            size * 96.0 / 72.0
+        }
+    }
 }
 
-fn parse_exit_keys(s: &str) -> utils::Sequence {
+/// The fonts of a single label.
+#[derive(Debug, Clone)]
+pub struct LabelFonts {
+    pub hint: FontConfig,
+    pub meta: FontConfig,
+}
+
+fn parse_key_sequence(s: &str) -> utils::Sequence {
     utils::Sequence::new(Some(s))
 }
 
 #[derive(Parser, Debug)]
 #[clap(name = "wmfocus", author, about, version)]
 pub struct AppConfig {
-    /// Use a specific TrueType font with this format: family:size
-    #[clap(short, long, default_value = "Mono:72", parse(try_from_str = parse_truetype_font))]
+    /// Use a specific font, either as Pango font description (eg "Iosevka Bold 40", size in points)
+    /// or with this format: family:size (size in pixels at 96 DPI); both are scaled to the DPI of
+    /// the monitor
+    #[clap(short, long, default_value = "Mono:72", parse(try_from_str = parse_font))]
     pub font: FontConfig,
 
+    /// Font size in pixels at 96 DPI that gets scaled to the DPI of the output (eg 40), in exact
+    /// pixels (eg 40px) or relative to the window height (eg 5%), overrides the size given in --font
+    #[clap(long = "fontsize", alias = "font-size", parse(try_from_str = parse_font_size))]
+    pub font_size: Option<FontSize>,
+
+    /// Use a specific font for window metadata in labels, same format as --font [default: --font
+    /// at half the size]
+    #[clap(long = "metafont", parse(try_from_str = parse_font))]
+    pub meta_font: Option<FontConfig>,
+
+    /// Label template, {hint}, {title} and {class} are replaced, eg '{hint} {class}: {title}'
+    #[clap(short, long, default_value = "{hint}", parse(try_from_str = parse_label))]
+    pub label: LabelTemplate,
+
+    /// Show application icons next to the hints
+    #[clap(
+        short,
+        long,
+        parse(try_from_str),
+        min_values = 0,
+        number_of_values = 1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value = "false",
+        hide_default_value = true,
+        value_name = "BOOL"
+    )]
+    pub icons: bool,
+
     /// Define a set of possbile values to use as hint characters
     #[clap(short = 'c', long = "chars", default_value = "sadfjklewcmpgh")]
     pub hint_chars: String,
 
+    /// Use the keys at these physical positions as hint characters instead of --chars, so they
+    /// stay in place with any keyboard layout. Either a preset (default, homerow, numbers) or a
+    /// list of XKB key names, eg AC01,AC02,AC03
+    #[clap(short = 'k', long = "keys", conflicts_with = "hint-chars", parse(try_from_str = parse_hint_keys))]
+    pub hint_keys: Option<HintKeys>,
+
     /// Add an additional margin around the text box (value is a factor of the box size)
     #[clap(short, long, default_value = "0.2")]
     pub margin: f32,
 
+    /// Padding around the text in pixels or relative to the font size instead of --margin, as x,y
+    /// or a single value for both, eg 10,0.2em
+    #[clap(long, conflicts_with = "margin", parse(try_from_str = parse_padding))]
+    pub padding: Option<Padding>,
+
+    /// Minimum size of the text box in pixels (width,height)
+    #[clap(long = "minsize", default_value = "0,0", parse(try_from_str = parse_size))]
+    pub min_size: Size,
+
+    /// Make all text boxes the same size
+    #[clap(
+        long,
+        parse(try_from_str),
+        min_values = 0,
+        number_of_values = 1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value = "false",
+        hide_default_value = true,
+        value_name = "BOOL"
+    )]
+    pub uniform: bool,
+
+    /// Load colors, fonts and layout from a theme: a built-in one (dark, light, high-contrast,
+    /// solarized), one from $XDG_CONFIG_HOME/wmfocus/themes/<name>.toml or a path to a theme
+    /// file; individual flags override the theme
+    #[clap(short, long, display_order = 48)]
+    pub theme: Option<String>,
+
     /// Text color (CSS notation)
     #[clap(long = "textcolor", display_order = 49, default_value = "#dddddd", parse(try_from_str = parse_color))]
     pub text_color: (f64, f64, f64, f64),
@@ -148,6 +476,92 @@ pub struct AppConfig {
     #[clap(long = "bgcolorcurrent", display_order = 54, default_value = "rgba(200, 200, 200, 0.9)", parse(try_from_str = parse_color))]
     pub bg_color_current: (f64, f64, f64, f64),
 
+    /// Border width in pixels
+    #[clap(long = "borderwidth", display_order = 55, default_value = "0")]
+    pub border_width: f64,
+
+    /// Border color (CSS notation)
+    #[clap(long = "bordercolor", display_order = 56, default_value = "#dddddd", parse(try_from_str = parse_color))]
+    pub border_color: (f64, f64, f64, f64),
+
+    /// Border color current window (CSS notation)
+    #[clap(long = "bordercolorcurrent", display_order = 57, default_value = "#333333", parse(try_from_str = parse_color))]
+    pub border_color_current: (f64, f64, f64, f64),
+
+    /// Corner radius of the box in pixels (needs a compositor)
+    #[clap(long, display_order = 58, default_value = "0")]
+    pub radius: f64,
+
+    /// Size of the drop shadow below the box in pixels (needs a compositor)
+    #[clap(long, display_order = 59, default_value = "0")]
+    pub shadow: u16,
+
+    /// Shadow color (CSS notation)
+    #[clap(long = "shadowcolor", display_order = 60, default_value = "rgba(0, 0, 0, 0.5)", parse(try_from_str = parse_color))]
+    pub shadow_color: (f64, f64, f64, f64),
+
+    /// Dim the screen below the labels with this color (CSS notation), eg "rgba(0, 0, 0, 0.5)"
+    #[clap(long, display_order = 61, parse(try_from_str = parse_color))]
+    pub dim: Option<(f64, f64, f64, f64)>,
+
+    /// Don't dim the current window when dimming with --dim
+    #[clap(
+        long = "dimexceptcurrent",
+        display_order = 62,
+        parse(try_from_str),
+        min_values = 0,
+        number_of_values = 1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value = "false",
+        hide_default_value = true,
+        value_name = "BOOL"
+    )]
+    pub dim_except_current: bool,
+
+    /// Width of the frame outlining windows whose hints match the keys typed so far, 0 to disable
+    #[clap(long = "framewidth", display_order = 63, default_value = "4")]
+    pub frame_width: u16,
+
+    /// Frame color (CSS notation)
+    #[clap(long = "framecolor", display_order = 64, default_value = "#5294e2", parse(try_from_str = parse_color))]
+    pub frame_color: (f64, f64, f64, f64),
+
+    /// Opacity factor for labels whose hints don't match the keys typed so far anymore
+    #[clap(long, display_order = 65, default_value = "0.3")]
+    pub fade: f64,
+
+    /// Hide labels whose hints don't match the keys typed so far anymore instead of fading them
+    #[clap(
+        long = "hidenonmatching",
+        display_order = 66,
+        parse(try_from_str),
+        min_values = 0,
+        number_of_values = 1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value = "false",
+        hide_default_value = true,
+        value_name = "BOOL"
+    )]
+    pub hide_nonmatching: bool,
+
+    /// Only show the remaining characters of matching hints and shrink their labels accordingly
+    #[clap(
+        long,
+        display_order = 67,
+        conflicts_with = "fill",
+        parse(try_from_str),
+        min_values = 0,
+        number_of_values = 1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value = "false",
+        hide_default_value = true,
+        value_name = "BOOL"
+    )]
+    pub shrink: bool,
+
     /// Horizontal alignment of the box inside the window
     #[clap(
         long = "halign",
@@ -168,28 +582,617 @@ pub struct AppConfig {
     )]
     pub vertical_align: VerticalAlign,
 
+    /// Part of the window to align the box in: the whole window, its title bar or tab (falls back
+    /// to the whole window if it has none) or its content without decorations
+    #[clap(
+        long,
+        display_order = 102,
+        default_value = "window",
+        ignore_case = true,
+        arg_enum
+    )]
+    pub anchor: Anchor,
+
     /// Completely fill out windows
-    #[clap(long, display_order = 102, conflicts_with_all(&["horizontal-align", "vertical-align", "margin", "offset"]))]
+    #[clap(long, display_order = 103, conflicts_with_all(&["horizontal-align", "vertical-align", "margin", "padding", "min-size", "uniform", "offset"]), parse(try_from_str), min_values = 0, number_of_values = 1, require_equals = true, default_missing_value = "true", default_value = "false", hide_default_value = true, value_name = "BOOL")]
     pub fill: bool,
 
+    /// Only show hints for windows on the output under the mouse cursor, the one with the focused
+    /// window or one picked by typing its hint first
+    #[clap(long, ignore_case = true, arg_enum)]
+    pub output: Option<OutputSelection>,
+
     /// Print the window id only but don't change focus
-    #[clap(short, long)]
+    #[clap(
+        short,
+        long,
+        parse(try_from_str),
+        min_values = 0,
+        number_of_values = 1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value = "false",
+        hide_default_value = true,
+        value_name = "BOOL"
+    )]
     pub print_only: bool,
 
+    /// Start in search mode: type to fuzzy match window titles and classes, Return selects
+    #[clap(
+        short,
+        long,
+        parse(try_from_str),
+        min_values = 0,
+        number_of_values = 1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value = "false",
+        hide_default_value = true,
+        value_name = "BOOL"
+    )]
+    pub search: bool,
+
+    /// Keep running in the background and show hints whenever wmfocus is run, which is faster.
+    /// Other options given to the daemon are the defaults for every run. Flags can be turned off
+    /// for a run again, eg with --shrink=false
+    #[clap(long)]
+    pub daemon: bool,
+
     /// Offset box from edge of window relative to alignment (x,y)
     #[clap(short, long, allow_hyphen_values = true, default_value = "0,0", parse(try_from_str = parse_offset))]
     pub offset: Offset,
 
     /// List of keys to exit application, sequences separator is space, key separator is '+', eg Control_L+g Shift_L+f
-    #[clap(short, long, parse(from_str = parse_exit_keys))]
+    #[clap(short, long, parse(from_str = parse_key_sequence))]
     pub exit_keys: Vec<utils::Sequence>,
+
+    /// What to do with the selected window, the action keys cycle through these
+    #[clap(short, long, default_value = "focus", ignore_case = true, arg_enum)]
+    pub action: Action,
+
+    /// What to do with a window when middle-clicking it or its label, left-click uses --action
+    #[clap(
+        long = "middleclick",
+        default_value = "swap",
+        ignore_case = true,
+        arg_enum
+    )]
+    pub middle_click_action: Action,
+
+    /// List of keys to select the best matching window, same format as --exit-keys
+    #[clap(long = "confirmkeys", default_values = &["Return", "KP_Enter"], parse(from_str = parse_key_sequence))]
+    pub confirm_keys: Vec<utils::Sequence>,
+
+    /// List of keys to remove the last typed character, same format as --exit-keys
+    #[clap(long = "backspacekeys", default_value = "BackSpace", parse(from_str = parse_key_sequence))]
+    pub backspace_keys: Vec<utils::Sequence>,
+
+    /// List of keys to clear all typed characters, same format as --exit-keys, eg Control_L+u
+    #[clap(long = "clearkeys", parse(from_str = parse_key_sequence))]
+    pub clear_keys: Vec<utils::Sequence>,
+
+    /// List of keys to cycle through the actions (focus, swap, close), same format as --exit-keys
+    #[clap(long = "actionkeys", default_value = "Tab", parse(from_str = parse_key_sequence))]
+    pub action_keys: Vec<utils::Sequence>,
+
+    /// List of keys to toggle showing window titles in labels, same format as --exit-keys
+    #[clap(long = "titlekeys", parse(from_str = parse_key_sequence))]
+    pub title_keys: Vec<utils::Sequence>,
+
+    /// List of keys to switch between hints and search mode, same format as --exit-keys. Keys that
+    /// type text are searched for instead when the query isn't empty
+    #[clap(long = "searchkeys", default_value = "slash", parse(from_str = parse_key_sequence))]
+    pub search_keys: Vec<utils::Sequence>,
+
+    /// Long names of the settings that were given on the command line or by a theme.
+    #[clap(skip)]
+    configured: HashSet<String>,
+}
+
+impl AppConfig {
+    /// Whether labels need windows with an alpha channel to be drawn in their actual shape.
+    pub fn transparent_labels(&self) -> bool {
+        self.radius > 0.0 || self.shadow > 0
+    }
+
+    /// Opacity hint for label windows. Labels with an alpha channel draw their own transparency.
+    pub fn label_opacity(&self) -> f64 {
+        if self.transparent_labels() {
+            1.0
+        } else {
+            self.bg_color.3
+        }
+    }
+
+    /// Fonts for the label of a window that is `window_height` pixels high on an output with `dpi`.
+    ///
+    /// Font sizes are meant for the usual 96 DPI and get scaled accordingly, unless they are given
+    /// in exact pixels or relative to the window height. The metadata font defaults to half the
+    /// hint font.
+    pub fn label_fonts(&self, dpi: f64, window_height: f64) -> LabelFonts {
+        let scale = dpi / 96.0;
+        let hint = match self.font_size {
+            Some(FontSize::Scaled(size)) => self.font.with_pixel_size(size * scale),
+            Some(FontSize::Pixels(size)) => self.font.with_pixel_size(size),
+            Some(FontSize::WindowHeight(fraction)) => {
+                self.font.with_pixel_size(fraction * window_height)
+            }
+            None => self.font.scaled(scale),
+        };
+        let meta = match &self.meta_font {
+            Some(font) => font.scaled(scale),
+            None => hint.scaled(0.5),
+        };
+        LabelFonts { hint, meta }
+    }
+}
+
+/// Apply `settings` from `source` (eg a theme) to `config`, skipping everything that was already
+/// configured by a source with higher precedence. Unknown settings are an error if `strict`,
+/// otherwise they are skipped with a warning.
+fn apply_settings(
+    config: &mut AppConfig,
+    settings: &Theme,
+    source: &str,
+    strict: bool,
+) -> Result<()> {
+    let mut applied = HashSet::new();
+    for (key, value) in &settings.values {
+        if config.configured.contains(key) {
+            continue;
+        }
+
+        let invalid = |e: String| {
+            anyhow!(
+                "Invalid value '{}' for setting '{}' in {}: {}",
+                value,
+                key,
+                source,
+                e
+            )
+        };
+        let flag = || value.parse::<bool>().map_err(|e| invalid(e.to_string()));
+        let float = || value.parse::<f64>().map_err(|e| invalid(e.to_string()));
+        let color = || parse_color(value).map_err(invalid);
+        match key.as_str() {
+            "font" => config.font = parse_font(value).map_err(|e| invalid(e.to_string()))?,
+            "fontsize" => config.font_size = Some(parse_font_size(value).map_err(invalid)?),
+            "metafont" => {
+                config.meta_font = Some(parse_font(value).map_err(|e| invalid(e.to_string()))?)
+            }
+            "label" => config.label = parse_label(value).map_err(invalid)?,
+            "icons" => config.icons = flag()?,
+            "margin" => config.margin = value.parse::<f32>().map_err(|e| invalid(e.to_string()))?,
+            "padding" => config.padding = Some(parse_padding(value).map_err(invalid)?),
+            "minsize" => config.min_size = parse_size(value).map_err(invalid)?,
+            "uniform" => config.uniform = flag()?,
+            "textcolor" => config.text_color = color()?,
+            "textcoloralt" => config.text_color_alt = color()?,
+            "bgcolor" => config.bg_color = color()?,
+            "textcolorcurrent" => config.text_color_current = color()?,
+            "textcolorcurrentalt" => config.text_color_current_alt = color()?,
+            "bgcolorcurrent" => config.bg_color_current = color()?,
+            "borderwidth" => config.border_width = float()?,
+            "bordercolor" => config.border_color = color()?,
+            "bordercolorcurrent" => config.border_color_current = color()?,
+            "radius" => config.radius = float()?,
+            "shadow" => config.shadow = value.parse::<u16>().map_err(|e| invalid(e.to_string()))?,
+            "shadowcolor" => config.shadow_color = color()?,
+            "dim" => config.dim = Some(color()?),
+            "dimexceptcurrent" => config.dim_except_current = flag()?,
+            "framewidth" => {
+                config.frame_width = value.parse::<u16>().map_err(|e| invalid(e.to_string()))?
+            }
+            "framecolor" => config.frame_color = color()?,
+            "fade" => config.fade = float()?,
+            "hidenonmatching" => config.hide_nonmatching = flag()?,
+            "shrink" => config.shrink = flag()?,
+            "halign" => {
+                config.horizontal_align = HorizontalAlign::from_str(value, true).map_err(invalid)?
+            }
+            "valign" => {
+                config.vertical_align = VerticalAlign::from_str(value, true).map_err(invalid)?
+            }
+            "anchor" => config.anchor = Anchor::from_str(value, true).map_err(invalid)?,
+            "offset" => config.offset = parse_offset(value).map_err(invalid)?,
+            _ if strict => bail!("Unknown setting '{}' in {}", key, source),
+            _ => {
+                warn!("Ignoring unknown setting '{}' in {}", key, source);
+                continue;
+            }
+        }
+        applied.insert(key.clone());
+    }
+
+    // A margin from this source replaces paddings from sources with lower precedence, just like
+    // the size of a font replaces font sizes.
+    if applied.contains("margin") {
+        applied.insert("padding".to_string());
+    }
+    if applied.contains("font") {
+        applied.insert("fontsize".to_string());
+    }
+    config.configured.extend(applied);
+    Ok(())
 }
 
-pub fn parse_args() -> AppConfig {
-    let mut config = AppConfig::parse();
+/// Apply settings from the X resource database (eg `wmfocus.bgcolor: #000000`) to `config`.
+/// These have the lowest precedence, so anything given on the command line or by a theme wins.
+/// The resources may well be shared with other versions of wmfocus, so unknown settings are only
+/// warned about.
+pub fn apply_xresources(config: &mut AppConfig, resources: &str) -> Result<()> {
+    apply_settings(
+        config,
+        &theme::from_xresources(resources),
+        "Xresources",
+        false,
+    )
+}
+
+/// Create the config from the command line in `matches`, remembering which settings were given
+/// explicitly.
+fn config_from_matches(matches: &ArgMatches) -> Result<AppConfig, clap::Error> {
+    let mut config = AppConfig::from_arg_matches(matches)?;
+    for arg in AppConfig::into_app().get_arguments() {
+        if let Some(long) = arg.get_long() {
+            if matches.occurrences_of(arg.get_name()) > 0 {
+                config.configured.insert(long.to_string());
+            }
+        }
+    }
+    if config.configured.contains("margin") {
+        config.configured.insert("padding".to_string());
+    }
+    if config.configured.contains("font") {
+        config.configured.insert("fontsize".to_string());
+    }
     if config.fill {
         config.horizontal_align = HorizontalAlign::Center;
         config.vertical_align = VerticalAlign::Center;
+        config.configured.insert("halign".to_string());
+        config.configured.insert("valign".to_string());
+    }
+    Ok(config)
+}
+
+/// Apply the theme given on the command line, if any.
+fn apply_theme(config: &mut AppConfig) -> Result<()> {
+    if let Some(name) = config.theme.clone() {
+        let theme =
+            theme::load(&name).with_context(|| format!("Couldn't load theme '{}'", name))?;
+        apply_settings(config, &theme, &format!("theme '{}'", name), true)?;
+    }
+    Ok(())
+}
+
+pub fn parse_args() -> Result<AppConfig> {
+    let matches = AppConfig::into_app().get_matches();
+    let mut config = config_from_matches(&matches).unwrap_or_else(|e| e.exit());
+    apply_theme(&mut config)?;
+    Ok(config)
+}
+
+/// Settings that can't be used along with others, by their long names. A request that gives
+/// either side doesn't get the other one from the daemon.
+const CONFLICTS: &[(&str, &[&str])] = &[
+    ("keys", &["chars"]),
+    ("padding", &["margin"]),
+    (
+        "fill",
+        &[
+            "halign", "valign", "margin", "padding", "minsize", "uniform", "offset", "shrink",
+        ],
+    ),
+];
+
+/// Take the settings that weren't given with a request from the `defaults` the daemon was started
+/// with.
+fn inherit_defaults(config: &mut AppConfig, defaults: AppConfig) {
+    let mut given = config.configured.clone();
+    for (setting, conflicting) in CONFLICTS {
+        if config.configured.contains(*setting) {
+            given.extend(conflicting.iter().map(|other| other.to_string()));
+        }
+        if conflicting
+            .iter()
+            .any(|other| config.configured.contains(*other))
+        {
+            given.insert(setting.to_string());
+        }
+    }
+
+    macro_rules! inherit {
+        ($($long:literal => $field:ident,)*) => {
+            $(
+                if defaults.configured.contains($long) && !given.contains($long) {
+                    config.$field = defaults.$field;
+                    config.configured.insert($long.to_string());
+                }
+            )*
+        };
+    }
+    inherit!(
+        "font" => font,
+        "fontsize" => font_size,
+        "metafont" => meta_font,
+        "label" => label,
+        "icons" => icons,
+        "chars" => hint_chars,
+        "keys" => hint_keys,
+        "margin" => margin,
+        "padding" => padding,
+        "minsize" => min_size,
+        "uniform" => uniform,
+        "textcolor" => text_color,
+        "textcoloralt" => text_color_alt,
+        "bgcolor" => bg_color,
+        "textcolorcurrent" => text_color_current,
+        "textcolorcurrentalt" => text_color_current_alt,
+        "bgcolorcurrent" => bg_color_current,
+        "borderwidth" => border_width,
+        "bordercolor" => border_color,
+        "bordercolorcurrent" => border_color_current,
+        "radius" => radius,
+        "shadow" => shadow,
+        "shadowcolor" => shadow_color,
+        "dim" => dim,
+        "dimexceptcurrent" => dim_except_current,
+        "framewidth" => frame_width,
+        "framecolor" => frame_color,
+        "fade" => fade,
+        "hidenonmatching" => hide_nonmatching,
+        "shrink" => shrink,
+        "halign" => horizontal_align,
+        "valign" => vertical_align,
+        "anchor" => anchor,
+        "fill" => fill,
+        "output" => output,
+        "print-only" => print_only,
+        "search" => search,
+        "offset" => offset,
+        "exit-keys" => exit_keys,
+        "action" => action,
+        "middleclick" => middle_click_action,
+        "confirmkeys" => confirm_keys,
+        "backspacekeys" => backspace_keys,
+        "clearkeys" => clear_keys,
+        "actionkeys" => action_keys,
+        "titlekeys" => title_keys,
+        "searchkeys" => search_keys,
+    );
+}
+
+/// Parse `args` sent to the daemon. Settings that aren't given in `args` or by their theme are
+/// taken from the `default_args` the daemon was started with. Unlike `parse_args`, invalid
+/// arguments are an error instead of exiting.
+pub fn parse_args_from(default_args: &[String], args: &[String]) -> Result<AppConfig> {
+    let parse = |args: &[String]| -> Result<AppConfig> {
+        let matches = AppConfig::into_app()
+            .try_get_matches_from(iter::once("wmfocus").chain(args.iter().map(String::as_str)))?;
+        let mut config = config_from_matches(&matches)?;
+        apply_theme(&mut config)?;
+        Ok(config)
+    };
+    let defaults = parse(default_args)?;
+    let mut config = parse(args)?;
+    inherit_defaults(&mut config, defaults);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_font() {
+        let font = parse_font("Mono:40").unwrap();
+        assert!(font.description.is_size_absolute());
+        assert_eq!(font.description.size(), 40 * pango::SCALE);
+
+        let font = parse_font("Iosevka").unwrap();
+        assert!(font.description.is_size_absolute());
+        assert_eq!(
+            f64::from(font.description.size()),
+            DEFAULT_FONT_SIZE * f64::from(pango::SCALE)
+        );
+    }
+
+    #[test]
+    fn test_parse_padding() {
+        let padding = parse_padding("10,0.5em").unwrap();
+        assert_eq!(padding.x, Length::Pixels(10.0));
+        assert_eq!(padding.y, Length::Em(0.5));
+
+        let padding = parse_padding("4px").unwrap();
+        assert_eq!(padding.x, Length::Pixels(4.0));
+        assert_eq!(padding.y, Length::Pixels(4.0));
+
+        assert!(parse_padding("1,2,3").is_err());
+        assert!(parse_padding("1,wide").is_err());
+    }
+
+    #[test]
+    fn test_parse_font_size() {
+        assert_eq!(parse_font_size("40").unwrap(), FontSize::Scaled(40.0));
+        assert_eq!(parse_font_size("40px").unwrap(), FontSize::Pixels(40.0));
+        assert_eq!(parse_font_size("5%").unwrap(), FontSize::WindowHeight(0.05));
+        assert!(parse_font_size("0%").is_err());
+        assert!(parse_font_size("large").is_err());
+    }
+
+    #[test]
+    fn test_label_fonts() {
+        let matches = AppConfig::into_app().get_matches_from(["wmfocus", "-f", "Mono:40"]);
+        let config = config_from_matches(&matches).unwrap();
+        let fonts = config.label_fonts(192.0, 1000.0);
+        assert_eq!(fonts.hint.pixel_size(), 80.0);
+        assert_eq!(fonts.meta.pixel_size(), 40.0);
+
+        let matches = AppConfig::into_app().get_matches_from(["wmfocus", "--font-size", "5%"]);
+        let config = config_from_matches(&matches).unwrap();
+        assert_eq!(config.label_fonts(192.0, 1000.0).hint.pixel_size(), 50.0);
+
+        // The default font is given in pixels at 96 DPI as well.
+        let matches = AppConfig::into_app().get_matches_from(["wmfocus"]);
+        let config = config_from_matches(&matches).unwrap();
+        assert_eq!(
+            config.label_fonts(192.0, 1000.0).hint.pixel_size(),
+            2.0 * DEFAULT_FONT_SIZE
+        );
+
+        // Sizes in exact pixels aren't scaled, unitless ones are.
+        let matches = AppConfig::into_app().get_matches_from(["wmfocus", "--font-size", "40px"]);
+        let config = config_from_matches(&matches).unwrap();
+        assert_eq!(config.label_fonts(192.0, 1000.0).hint.pixel_size(), 40.0);
+        let matches = AppConfig::into_app().get_matches_from(["wmfocus", "--font-size", "40"]);
+        let config = config_from_matches(&matches).unwrap();
+        assert_eq!(config.label_fonts(192.0, 1000.0).hint.pixel_size(), 80.0);
+    }
+
+    #[test]
+    fn test_parse_args_from() {
+        let to_strings = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let default_args = to_strings(&[
+            "--daemon",
+            "--bgcolor",
+            "#ff0000",
+            "-e",
+            "q",
+            "-e",
+            "Escape",
+        ]);
+
+        let config = parse_args_from(&default_args, &[]).unwrap();
+        assert_eq!(config.bg_color, (1.0, 0.0, 0.0, 1.0));
+        assert_eq!(config.exit_keys.len(), 2);
+        assert!(!config.daemon);
+
+        // Options given to the client win over the ones of the daemon.
+        let args = to_strings(&["--bgcolor", "#00ff00", "--fill"]);
+        let config = parse_args_from(&default_args, &args).unwrap();
+        assert_eq!(config.bg_color, (0.0, 1.0, 0.0, 1.0));
+        assert_eq!(config.exit_keys.len(), 2);
+        assert!(config.fill);
+
+        // Options that conflict with ones given to the client aren't taken from the daemon.
+        let default_args = to_strings(&["--chars", "abc", "--halign", "left", "--margin", "0.5"]);
+        let args = to_strings(&["--keys", "homerow", "--fill"]);
+        let config = parse_args_from(&default_args, &args).unwrap();
+        assert!(config.hint_keys.is_some());
+        assert_eq!(config.horizontal_align, HorizontalAlign::Center);
+        assert_eq!(config.margin, 0.2);
+        let config = parse_args_from(&default_args, &to_strings(&["--padding", "4"])).unwrap();
+        assert_eq!(config.hint_chars, "abc");
+        assert_eq!(config.horizontal_align, HorizontalAlign::Left);
+        assert_eq!(config.margin, 0.2);
+
+        // Flags of the daemon can be turned off again.
+        let default_args = to_strings(&["--shrink", "--dim", "#000000"]);
+        let config = parse_args_from(&default_args, &to_strings(&["--shrink=false"])).unwrap();
+        assert!(!config.shrink);
+        let config = parse_args_from(&default_args, &to_strings(&["--dimexceptcurrent"])).unwrap();
+        assert!(config.shrink);
+        assert!(config.dim.is_some() && config.dim_except_current);
+
+        assert!(parse_args_from(&[], &to_strings(&["--no-such-option"])).is_err());
+    }
+
+    #[test]
+    fn test_apply_settings() {
+        let matches = AppConfig::into_app().get_matches_from(["wmfocus", "--bgcolor", "#ff0000"]);
+        let mut config = config_from_matches(&matches).unwrap();
+        let theme = Theme {
+            values: [
+                ("bgcolor", "#000000"),
+                ("textcolor", "#00ff00"),
+                ("radius", "4"),
+            ]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        };
+        apply_settings(&mut config, &theme, "theme", true).unwrap();
+        assert_eq!(config.bg_color, (1.0, 0.0, 0.0, 1.0));
+        assert_eq!(config.text_color, (0.0, 1.0, 0.0, 1.0));
+        assert_eq!(config.radius, 4.0);
+
+        // Xresources come last and must not override the command line or the theme.
+        apply_xresources(
+            &mut config,
+            "wmfocus.textcolor: #0000ff\nwmfocus.bordercolor: #0000ff\n",
+        )
+        .unwrap();
+        assert_eq!(config.text_color, (0.0, 1.0, 0.0, 1.0));
+        assert_eq!(config.border_color, (0.0, 0.0, 1.0, 1.0));
+
+        // Settings other versions might know about don't stop Xresources from being used.
+        apply_xresources(
+            &mut config,
+            "wmfocus.nosuchsetting: 1
+wmfocus.shadow: 2
+",
+        )
+        .unwrap();
+        assert_eq!(config.shadow, 2);
+
+        let theme = Theme {
+            values: [("chars".to_string(), "abc".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        assert!(apply_settings(&mut config, &theme, "theme", true).is_err());
+
+        // Parse the built-in themes directly as a user theme of the same name would win in load.
+        for (name, source) in theme::BUILTIN_THEMES {
+            let mut config = config_from_matches(&matches).unwrap();
+            let theme = theme::parse(source).unwrap();
+            assert!(apply_settings(&mut config, &theme, name, true).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_hint_keys() {
+        assert_eq!(parse_hint_keys("homerow").unwrap().names.len(), 10);
+        assert_eq!(
+            parse_hint_keys("AC01, AB10").unwrap().names,
+            vec!["AC01".to_string(), "AB10".to_string()]
+        );
+        assert!(parse_hint_keys("AC01,,AC02").is_err());
+        assert!(parse_hint_keys("a;").is_err());
+    }
+
+    #[test]
+    fn test_toggled_label() {
+        let label = parse_label("{hint}").unwrap().toggled();
+        assert_eq!(
+            label.parts,
+            vec![
+                LabelPart::Hint,
+                LabelPart::Text(" ".to_string()),
+                LabelPart::Title
+            ]
+        );
+        let label = parse_label("{hint}: {class}").unwrap().toggled();
+        assert_eq!(label.parts, vec![LabelPart::Hint]);
+    }
+
+    #[test]
+    fn test_parse_label() {
+        let label = parse_label("{hint} {class}: {title}").unwrap();
+        assert_eq!(
+            label.parts,
+            vec![
+                LabelPart::Hint,
+                LabelPart::Text(" ".to_string()),
+                LabelPart::Class,
+                LabelPart::Text(": ".to_string()),
+                LabelPart::Title,
+            ]
+        );
+
+        assert!(parse_label("{title}").is_err());
+        assert!(parse_label("{hint}{hint}").is_err());
+        assert!(parse_label("{hint} {foo}").is_err());
+        assert!(parse_label("{hint} {title").is_err());
     }
-    config
 }
@@ -0,0 +1,146 @@
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::env;
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::args::{self, AppConfig};
+
+/// Separates the arguments in a request and the output from an error in a response.
+const SEPARATOR: char = '\0';
+
+/// How long reading a request and writing a response may take, so that a client that doesn't
+/// finish its request can't block the daemon.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Path of the socket the daemon for `display` listens on in `runtime_dir`.
+fn socket_path_for(runtime_dir: &Path, display: &str) -> PathBuf {
+    runtime_dir.join(format!("wmfocus{}.sock", display))
+}
+
+/// Path of the socket the daemon for the current display listens on. It's only ever put into
+/// `$XDG_RUNTIME_DIR` as that can't be accessed by other users.
+fn socket_path() -> Result<PathBuf> {
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR")
+        .context("XDG_RUNTIME_DIR has to be set for the socket of the daemon")?;
+    let display = env::var("DISPLAY").unwrap_or_default();
+    Ok(socket_path_for(Path::new(&runtime_dir), &display))
+}
+
+/// Ask a running daemon to show hints with `args` and print what it outputs.
+///
+/// Returns `false` if no daemon is running.
+pub fn request(args: &[String]) -> Result<bool> {
+    let path = match socket_path() {
+        Ok(path) => path,
+        Err(_) => return Ok(false),
+    };
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e).context("Couldn't connect to the daemon"),
+    };
+    let request = args.join(&SEPARATOR.to_string());
+    stream
+        .write_all(request.as_bytes())
+        .context("Couldn't send request to the daemon")?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .context("Couldn't read response from the daemon")?;
+    let (output, error) = match response.split_once(SEPARATOR) {
+        Some((output, error)) => (output, Some(error)),
+        None => (response.as_str(), None),
+    };
+    print!("{}", output);
+    io::stdout().flush()?;
+    if let Some(error) = error {
+        bail!("{}", error);
+    }
+    Ok(true)
+}
+
+/// Listen for requests and show hints for each one with `show_hints`, which writes its output to
+/// the given writer. The `default_args` the daemon was started with apply to all requests unless
+/// they override them. Only one daemon can run per display.
+pub fn serve<F>(default_args: &[String], mut show_hints: F) -> Result<()>
+where
+    F: FnMut(AppConfig, &mut dyn Write) -> Result<()>,
+{
+    let path = socket_path()?;
+    if UnixStream::connect(&path).is_ok() {
+        bail!("wmfocus is already running as daemon on {}", path.display());
+    }
+    // A socket left behind by a daemon that didn't exit cleanly.
+    if path.exists() {
+        fs::remove_file(&path)
+            .with_context(|| format!("Couldn't remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Couldn't listen on {}", path.display()))?;
+    fs::set_permissions(&path, Permissions::from_mode(0o600))
+        .with_context(|| format!("Couldn't restrict access to {}", path.display()))?;
+    info!("Listening on {}", path.display());
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Couldn't accept connection: {}", e);
+                continue;
+            }
+        };
+        let timeouts = stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)));
+        if let Err(e) = timeouts {
+            warn!("Couldn't set timeouts: {}", e);
+            continue;
+        }
+        let mut request = String::new();
+        if let Err(e) = stream.read_to_string(&mut request) {
+            warn!("Couldn't read request: {}", e);
+            continue;
+        }
+        let args: Vec<String> = if request.is_empty() {
+            vec![]
+        } else {
+            request.split(SEPARATOR).map(str::to_string).collect()
+        };
+        info!("Showing hints for {:?}", args);
+
+        let mut output = vec![];
+        let result = args::parse_args_from(default_args, &args)
+            .and_then(|app_config| show_hints(app_config, &mut output));
+        if let Err(e) = &result {
+            warn!("{:#}", e);
+            write!(output, "{}{:#}", SEPARATOR, e)?;
+        }
+        if let Err(e) = stream.write_all(&output) {
+            warn!("Couldn't send response: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_path_for() {
+        assert_eq!(
+            socket_path_for(Path::new("/run/user/1000"), ":0"),
+            PathBuf::from("/run/user/1000/wmfocus:0.sock")
+        );
+    }
+}
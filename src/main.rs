@@ -1,11 +1,18 @@
 use anyhow::{Context, Result};
 use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::iter::Iterator;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, Write};
+use std::iter::{self, Iterator};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 use xkbcommon::xkb;
 
 mod args;
+mod daemon;
+mod output;
+mod placement;
+mod theme;
 mod utils;
 
 #[cfg(feature = "i3")]
@@ -17,88 +24,797 @@ mod wm_i3;
 #[cfg(feature = "i3")]
 use crate::wm_i3 as wm;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DesktopWindow {
     id: i64,
     x_window_id: Option<i32>,
     pos: (i32, i32),
     size: (i32, i32),
     is_focused: bool,
+    title: String,
+    class: String,
+    /// Name of the output the window is on, if the window manager knows it.
+    output: Option<String>,
+    /// Title bar or tab of the window, if it has one.
+    titlebar: Option<(i32, i32, i32, i32)>,
+    /// Content of the window without decorations, if it's visible.
+    content: Option<(i32, i32, i32, i32)>,
+}
+
+impl DesktopWindow {
+    /// Position and size in the format (x, y, w, h).
+    pub fn rect(&self) -> (i32, i32, i32, i32) {
+        (self.pos.0, self.pos.1, self.size.0, self.size.1)
+    }
+
+    /// Rect to align labels in for `anchor`. Windows without title bar use the whole window
+    /// instead, just like hidden tabs use their tab instead of the content.
+    pub fn anchor_rect(&self, anchor: args::Anchor) -> (i32, i32, i32, i32) {
+        match anchor {
+            args::Anchor::Window => self.rect(),
+            args::Anchor::Titlebar => self.titlebar.unwrap_or_else(|| self.rect()),
+            args::Anchor::Content => self
+                .content
+                .or(self.titlebar)
+                .unwrap_or_else(|| self.rect()),
+        }
+    }
+}
+
+/// Hints that windows had before for each set of hint characters, so that windows can keep their
+/// hints from one run to the next.
+#[derive(Debug, Clone, Default)]
+pub struct HintMemory {
+    by_chars: HashMap<String, HashMap<i64, String>>,
+    /// Characters of the hints that were shown last.
+    shown_chars: Option<String>,
+}
+
+impl HintMemory {
+    /// Hints made of `hint_chars`, which are about to be shown.
+    fn for_chars(&mut self, hint_chars: &str) -> &mut HashMap<i64, String> {
+        self.shown_chars = Some(hint_chars.to_string());
+        self.by_chars.entry(hint_chars.to_string()).or_default()
+    }
+}
+
+/// Windows kept up to date in the background by the daemon along with their hints, so that both
+/// are ready when hints are requested.
+#[derive(Debug, Default)]
+pub struct WindowCache {
+    /// Windows sorted by position, `None` while they aren't known.
+    windows: Option<Vec<DesktopWindow>>,
+    /// Hints for the windows for every set of hint characters asked for so far.
+    hints: HintMemory,
+}
+
+impl WindowCache {
+    pub fn new(hint_chars: &str) -> WindowCache {
+        let mut cache = WindowCache::default();
+        cache
+            .hints
+            .by_chars
+            .insert(hint_chars.to_string(), HashMap::new());
+        cache
+    }
+
+    /// Replace the cached windows with `desktop_windows` and assign their hints right away.
+    pub fn update(&mut self, desktop_windows: Vec<DesktopWindow>) {
+        self.windows = Some(utils::sort_by_pos(desktop_windows));
+        self.assign_hints();
+    }
+
+    /// Take over the hints a run of `show_hints` ended up showing. They win over the hints other
+    /// windows had before.
+    pub fn remember(&mut self, mut hint_memory: HintMemory) {
+        if let Some(hint_chars) = hint_memory.shown_chars {
+            let shown = hint_memory.by_chars.remove(&hint_chars).unwrap_or_default();
+            let hints = self.hints.by_chars.entry(hint_chars).or_default();
+            hints.retain(|id, hint| !shown.contains_key(id) && !shown.values().any(|h| h == hint));
+            hints.extend(shown);
+        }
+        self.assign_hints();
+    }
+
+    /// Assign hints made of each set of hint characters to the cached windows, keeping the ones
+    /// they had before where possible.
+    fn assign_hints(&mut self) {
+        let window_ids: Vec<i64> = match &self.windows {
+            Some(desktop_windows) => desktop_windows.iter().map(|dw| dw.id).collect(),
+            None => return,
+        };
+        for (hint_chars, hints) in &mut self.hints.by_chars {
+            match utils::assign_hints(&window_ids, hint_chars, hints) {
+                Ok(assigned) => *hints = window_ids.iter().copied().zip(assigned).collect(),
+                Err(e) => warn!("Couldn't assign hints: {:#}", e),
+            }
+        }
+    }
+
+    /// Forget the cached windows, eg because they can't be kept up to date anymore.
+    pub fn clear(&mut self) {
+        self.windows = None;
+    }
+}
+
+/// A `WindowCache` shared with the threads that keep it up to date.
+pub type SharedWindowCache = Arc<Mutex<WindowCache>>;
+
+/// What hints depend on besides the windows. The daemon keeps it between runs and only queries
+/// the parts again that the X server reported changes for.
+pub struct XState {
+    keyboard: utils::Keyboard,
+    /// The X resource database, if there is one.
+    resources: Option<String>,
+    outputs: Vec<output::Output>,
+}
+
+impl XState {
+    pub fn new(conn: &xcb::Connection, screen: &xcb::Screen) -> Result<XState> {
+        Ok(XState {
+            keyboard: utils::Keyboard::new(conn).context("Couldn't set up keyboard")?,
+            resources: utils::get_xresources(conn, screen),
+            outputs: output::get_outputs(conn, screen),
+        })
+    }
+
+    /// Query the part that `change` was reported for again.
+    pub fn update(
+        &mut self,
+        conn: &xcb::Connection,
+        screen: &xcb::Screen,
+        change: utils::SetupChange,
+    ) -> Result<()> {
+        match change {
+            utils::SetupChange::Keyboard => {
+                self.keyboard = utils::Keyboard::new(conn).context("Couldn't set up keyboard")?
+            }
+            utils::SetupChange::Resources => self.resources = utils::get_xresources(conn, screen),
+            utils::SetupChange::Outputs => self.outputs = output::get_outputs(conn, screen),
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct RenderWindow<'a> {
     desktop_window: &'a DesktopWindow,
+    xcb_window_id: u32,
     cairo_context: cairo::Context,
+    label: Vec<utils::LabelSegment>,
+    icon: Option<(cairo::ImageSurface, (f64, f64, f64, f64))>,
+    fonts: args::LabelFonts,
     draw_pos: (f64, f64),
     rect: (i32, i32, i32, i32),
+    full_rect: (i32, i32, i32, i32),
+    /// The label to switch to when toggling the title display together with the changes of the
+    /// box width and the text position.
+    alt_label: Option<(Vec<utils::LabelSegment>, i32, f64)>,
+    /// Line leading to the window if the label had to be placed away from it.
+    leader: Option<(u32, cairo::Context)>,
+    /// Whether the label is mapped. Hidden labels can't be clicked.
+    visible: bool,
 }
 
+/// Window showing the current action and the query in search mode.
+#[derive(Debug)]
+pub struct QueryWindow {
+    xcb_window_id: u32,
+    cairo_context: cairo::Context,
+    font: args::FontConfig,
+    draw_pos: (f64, f64),
+}
+
+/// Overlay windows created while showing hints. They get destroyed along with their colormap when
+/// this is dropped, so that the daemon doesn't leave any behind, even if showing hints fails
+/// halfway.
 #[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
-fn main() -> Result<()> {
-    pretty_env_logger::init();
-    let app_config = args::parse_args();
+struct Overlays<'a> {
+    conn: &'a xcb::Connection,
+    xcb_window_ids: Vec<u32>,
+    /// Colormap shared by all windows with an alpha channel, created along with the first one.
+    argb_colormap: Option<u32>,
+}
 
-    // Get the windows from each specific window manager implementation.
-    let desktop_windows_raw = wm::get_windows().context("Couldn't get desktop windows")?;
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+impl<'a> Overlays<'a> {
+    fn new(conn: &'a xcb::Connection) -> Overlays<'a> {
+        Overlays {
+            conn,
+            xcb_window_ids: vec![],
+            argb_colormap: None,
+        }
+    }
+
+    /// Create an overlay window like `utils::create_overlay_window` does.
+    fn create(
+        &mut self,
+        screen: &xcb::Screen,
+        rect: (i16, i16, u16, u16),
+        opacity: f64,
+        argb: bool,
+    ) -> Result<(u32, cairo::Context)> {
+        let argb_colormap = match (argb, self.argb_colormap) {
+            (false, _) => None,
+            (true, Some(colormap)) => Some(colormap),
+            (true, None) => {
+                let colormap = utils::create_argb_colormap(self.conn, screen)?;
+                self.argb_colormap = Some(colormap);
+                Some(colormap)
+            }
+        };
+        let (xcb_window_id, cairo_context) =
+            utils::create_overlay_window(self.conn, screen, rect, opacity, argb_colormap)?;
+        self.xcb_window_ids.push(xcb_window_id);
+        Ok((xcb_window_id, cairo_context))
+    }
+}
+
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+impl Drop for Overlays<'_> {
+    fn drop(&mut self) {
+        for xcb_window_id in &self.xcb_window_ids {
+            xcb::destroy_window(self.conn, *xcb_window_id);
+        }
+        if let Some(colormap) = self.argb_colormap {
+            xcb::free_colormap(self.conn, colormap);
+        }
+        self.conn.flush();
+    }
+}
+
+/// Let the user pick one of `outputs` by typing the hint shown in its middle or by clicking it.
+/// Returns `None` if picking was cancelled.
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn pick_output<'a>(
+    conn: &xcb::Connection,
+    screen: &xcb::Screen,
+    app_config: &args::AppConfig,
+    keyboard: &mut utils::Keyboard,
+    hint_keys: &HashMap<xkb::Keycode, char>,
+    outputs: &[&'a output::Output],
+    dpi_for: impl Fn(Option<&output::Output>) -> f64,
+) -> Result<Option<&'a output::Output>> {
+    // Output labels are drawn like window labels, just twice as large and with the output name
+    // in place of the window title.
+    let output_windows: Vec<_> = outputs
+        .iter()
+        .map(|output| DesktopWindow {
+            id: 0,
+            x_window_id: None,
+            pos: (output.rect.0, output.rect.1),
+            size: (output.rect.2, output.rect.3),
+            is_focused: false,
+            title: output.name.clone(),
+            class: String::new(),
+            output: Some(output.name.clone()),
+            titlebar: None,
+            content: None,
+        })
+        .collect();
+    let template = args::LabelTemplate {
+        parts: vec![
+            args::LabelPart::Hint,
+            args::LabelPart::Text(" ".to_string()),
+            args::LabelPart::Title,
+        ],
+    };
 
+    let mut overlays = Overlays::new(conn);
+    let mut render_windows = HashMap::new();
+    for (output, desktop_window) in outputs.iter().zip(&output_windows) {
+        let hint = utils::get_next_hint(
+            render_windows.keys().collect(),
+            &app_config.hint_chars,
+            outputs.len(),
+        )
+        .context("Couldn't get next hint")?;
+        let fonts = app_config.label_fonts(dpi_for(Some(output)), f64::from(output.rect.3));
+        let fonts = args::LabelFonts {
+            hint: fonts.hint.scaled(2.0),
+            meta: fonts.meta.scaled(2.0),
+        };
+        let max_label_width = utils::max_label_width(app_config, &fonts.hint, output.rect.2);
+        let (label, text_extents) = utils::layout_label(
+            app_config,
+            &fonts,
+            &template,
+            &hint,
+            desktop_window,
+            max_label_width,
+        )
+        .context("Couldn't create extents for label")?;
+        let (box_width, box_height) = utils::box_size(app_config, &fonts.hint, &text_extents);
+        let (width, height) = (box_width.round() as u16, box_height.round() as u16);
+        let draw_pos = (
+            (box_width - text_extents.width) / 2.0 - text_extents.x_bearing,
+            (box_height - text_extents.height) / 2.0 - text_extents.y_bearing,
+        );
+        let rect = (
+            output.rect.0 + (output.rect.2 - i32::from(width)) / 2,
+            output.rect.1 + (output.rect.3 - i32::from(height)) / 2,
+            i32::from(width + app_config.shadow),
+            i32::from(height + app_config.shadow),
+        );
+        let (xcb_window_id, cairo_context) = overlays.create(
+            screen,
+            (rect.0 as i16, rect.1 as i16, rect.2 as u16, rect.3 as u16),
+            app_config.label_opacity(),
+            app_config.transparent_labels(),
+        )?;
+        xcb::map_window(conn, xcb_window_id);
+        let render_window = RenderWindow {
+            desktop_window,
+            xcb_window_id,
+            cairo_context,
+            label,
+            icon: None,
+            fonts,
+            draw_pos,
+            rect,
+            full_rect: rect,
+            visible: true,
+            alt_label: None,
+            leader: None,
+        };
+        render_windows.insert(hint, (*output, render_window));
+    }
+    conn.flush();
+
+    utils::snatch_keyboard(conn, screen, Duration::from_secs(1))?;
+    utils::snatch_mouse(conn, screen, Duration::from_secs(1))?;
+
+    let mut sequence = utils::Sequence::new(None);
+    let picked = loop {
+        let event = match conn.wait_for_event() {
+            Some(event) => event,
+            None => break None,
+        };
+        match event.response_type() {
+            xcb::EXPOSE => {
+                for (hint, (_, rw)) in &render_windows {
+                    utils::draw_hint_text(rw, app_config, hint, "")
+                        .context("Couldn't draw hint text")?;
+                }
+                conn.flush();
+            }
+            xcb::BUTTON_PRESS => {
+                let button_press: &xcb::ButtonPressEvent = unsafe { xcb::cast_event(&event) };
+                let point = (
+                    i32::from(button_press.root_x()),
+                    i32::from(button_press.root_y()),
+                );
+                match button_press.detail() {
+                    1 => {
+                        break outputs
+                            .iter()
+                            .copied()
+                            .find(|o| utils::contains(o.rect, point))
+                    }
+                    3 => break None,
+                    _ => {}
+                }
+            }
+            xcb::KEY_RELEASE => {
+                let key = keyboard.lookup(&event);
+                sequence.remove(&key.name);
+            }
+            xcb::KEY_PRESS => {
+                let key = keyboard.lookup(&event);
+                sequence.push(key.name.clone());
+                if key.keysym == xkb::KEY_Escape || app_config.exit_keys.contains(&sequence) {
+                    info!("{:?} is exit sequence", sequence);
+                    break None;
+                }
+                let text = match hint_keys.get(&key.keycode) {
+                    Some(c) => c.to_string(),
+                    None => key.text,
+                };
+                if let Some((output, _)) = render_windows.get(&text) {
+                    break Some(*output);
+                }
+            }
+            _ => {}
+        }
+    };
+
+    Ok(picked)
+}
+
+/// Apply `action` to `desktop_window` or just print its id to `out`, depending on `app_config`.
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn select_window(
+    app_config: &args::AppConfig,
+    out: &mut dyn Write,
+    action: args::Action,
+    desktop_window: &DesktopWindow,
+) -> Result<()> {
+    if app_config.print_only {
+        writeln!(out, "0x{:x}", desktop_window.x_window_id.unwrap_or(0))?;
+        return Ok(());
+    }
+    match action {
+        args::Action::Focus => wm::focus_window(desktop_window).context("Couldn't focus window"),
+        args::Action::Swap => wm::swap_window(desktop_window).context("Couldn't swap window"),
+        args::Action::Close => wm::close_window(desktop_window).context("Couldn't close window"),
+    }
+}
+
+/// Show the query window while there is anything to show in it and redraw it.
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn update_prompt(
+    conn: &xcb::Connection,
+    app_config: &args::AppConfig,
+    qw: &QueryWindow,
+    action: args::Action,
+    query: Option<&str>,
+) -> Result<()> {
+    if action == args::Action::Focus && query.is_none() {
+        xcb::unmap_window(conn, qw.xcb_window_id);
+    } else {
+        xcb::map_window(conn, qw.xcb_window_id);
+        utils::draw_prompt(qw, app_config, action, query).context("Couldn't draw prompt")?;
+    }
+    conn.flush();
+    Ok(())
+}
+
+/// Switch the labels of all `render_windows` between their template and the toggled one.
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn toggle_labels(
+    conn: &xcb::Connection,
+    app_config: &args::AppConfig,
+    render_windows: &mut HashMap<String, RenderWindow>,
+) -> Result<()> {
+    for rw in render_windows.values_mut() {
+        if let Some((label, width_change, x_change)) = rw.alt_label.take() {
+            let old_label = std::mem::replace(&mut rw.label, label);
+            rw.draw_pos.0 += x_change;
+            if let Some((_, icon_rect)) = &mut rw.icon {
+                icon_rect.0 += x_change;
+            }
+            rw.full_rect.0 -= utils::align_shift(app_config.horizontal_align, width_change);
+            rw.full_rect.2 += width_change;
+            rw.alt_label = Some((old_label, -width_change, -x_change));
+            utils::move_render_window(conn, rw, rw.full_rect)?;
+        }
+    }
+    Ok(())
+}
+
+/// Map or unmap the label of `rw` together with its leader line.
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn show_label(conn: &xcb::Connection, rw: &mut RenderWindow, visible: bool) {
+    rw.visible = visible;
+    let leader = rw.leader.as_ref().map(|(xcb_window_id, _)| *xcb_window_id);
+    for xcb_window_id in iter::once(rw.xcb_window_id).chain(leader) {
+        if visible {
+            xcb::map_window(conn, xcb_window_id);
+        } else {
+            xcb::unmap_window(conn, xcb_window_id);
+        }
+    }
+}
+
+/// Update all labels after `pressed_keys` changed. Windows that can still be selected get outlined
+/// while the labels of all others are faded out or hidden.
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn update_labels(
+    conn: &xcb::Connection,
+    app_config: &args::AppConfig,
+    render_windows: &mut HashMap<String, RenderWindow>,
+    frame_windows: &HashMap<String, (u32, cairo::Context)>,
+    pressed_keys: &str,
+) -> Result<()> {
+    for (hint, rw) in render_windows.iter_mut() {
+        let matching = hint.starts_with(pressed_keys);
+        if let Some((frame_window_id, _)) = frame_windows.get(hint) {
+            if matching && !pressed_keys.is_empty() {
+                xcb::map_window(conn, *frame_window_id);
+            } else {
+                xcb::unmap_window(conn, *frame_window_id);
+            }
+        }
+        if matching {
+            utils::set_opacity(conn, rw.xcb_window_id, app_config.label_opacity())?;
+            show_label(conn, rw, true);
+        } else if app_config.hide_nonmatching {
+            show_label(conn, rw, false);
+        } else {
+            utils::set_opacity(
+                conn,
+                rw.xcb_window_id,
+                app_config.label_opacity() * app_config.fade,
+            )?;
+        }
+
+        // Shrunk labels only show the remaining hint characters, all others the whole hint.
+        let rect = utils::label_rect(
+            rw.full_rect,
+            app_config.horizontal_align,
+            app_config.shrink,
+            hint,
+            pressed_keys,
+            |text| utils::hint_advance(&rw.fonts.hint, text),
+        )?;
+        utils::move_render_window(conn, rw, rect)?;
+
+        utils::draw_hint_text(rw, app_config, hint, pressed_keys)
+            .context("Couldn't draw hint text")?;
+        conn.flush();
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn show_hints(
+    conn: &xcb::Connection,
+    screen_num: i32,
+    mut app_config: args::AppConfig,
+    desktop_windows_raw: Vec<DesktopWindow>,
+    x_state: &mut XState,
+    hint_memory: &mut HintMemory,
+    out: &mut dyn Write,
+) -> Result<()> {
     // Sort by position to make hint position more deterministic.
-    let desktop_windows = utils::sort_by_pos(desktop_windows_raw);
+    let mut desktop_windows = utils::sort_by_pos(desktop_windows_raw);
 
-    let (conn, screen_num) = xcb::Connection::connect(None).context("No Xorg connection")?;
     let setup = conn.get_setup();
     let screen = setup
         .roots()
         .nth(screen_num as usize)
         .context("Couldn't get screen")?;
 
-    let values = [
-        (xcb::CW_BACK_PIXEL, screen.black_pixel()),
-        (
-            xcb::CW_EVENT_MASK,
-            xcb::EVENT_MASK_EXPOSURE
-                | xcb::EVENT_MASK_KEY_PRESS
-                | xcb::EVENT_MASK_BUTTON_PRESS
-                | xcb::EVENT_MASK_BUTTON_RELEASE,
-        ),
-        (xcb::CW_OVERRIDE_REDIRECT, 1),
-    ];
-
-    // Assemble RenderWindows from DesktopWindows.
-    let mut render_windows = HashMap::new();
-    for desktop_window in &desktop_windows {
+    let mut overlays = Overlays::new(conn);
+
+    let resources = &x_state.resources;
+    if let Some(resources) = resources {
+        args::apply_xresources(&mut app_config, resources)
+            .context("Couldn't apply settings from Xresources")?;
+    }
+
+    // Keys are translated according to the keyboard layout of the X server. Hint keys given by
+    // their position are shown with the characters they type in that layout.
+    let keyboard = &mut x_state.keyboard;
+    let hint_keys: HashMap<_, _> = match &app_config.hint_keys {
+        Some(keys) => {
+            let hint_keys = keyboard.hint_keys(&keys.names)?;
+            app_config.hint_chars = hint_keys.iter().map(|(_, c)| c).collect();
+            hint_keys.into_iter().collect()
+        }
+        None => HashMap::new(),
+    };
+
+    // Fonts are scaled to the DPI of the output they are shown on. Outputs that don't report their
+    // physical size use Xft.dpi instead.
+    let outputs = &x_state.outputs;
+    let fallback_dpi = resources
+        .as_deref()
+        .and_then(output::parse_xft_dpi)
+        .unwrap_or(96.0);
+    let dpi_for = |output: Option<&output::Output>| {
+        output.and_then(output::Output::dpi).unwrap_or(fallback_dpi)
+    };
+
+    // Only show hints on one monitor if asked to. Picking one first is only worth it if there's
+    // more than one output with windows on it.
+    let selected_output = match app_config.output {
+        Some(args::OutputSelection::Cursor) => {
+            let pointer = utils::get_pointer_position(conn, &screen)?;
+            output::output_at(outputs, pointer)
+        }
+        Some(args::OutputSelection::Focused) => desktop_windows
+            .iter()
+            .find(|dw| dw.is_focused)
+            .and_then(|dw| output::output_for_window(outputs, dw)),
+        Some(args::OutputSelection::Pick) => {
+            let candidates: Vec<_> = outputs
+                .iter()
+                .filter(|output| {
+                    desktop_windows
+                        .iter()
+                        .any(|dw| output::output_for_window(outputs, dw) == Some(*output))
+                })
+                .collect();
+            if candidates.len() > 1 {
+                let picked = pick_output(
+                    conn,
+                    &screen,
+                    &app_config,
+                    keyboard,
+                    &hint_keys,
+                    &candidates,
+                    dpi_for,
+                )?;
+                if picked.is_none() {
+                    info!("No output picked, exiting");
+                    return Ok(());
+                }
+                picked
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+    if let Some(selected_output) = selected_output {
+        info!("Only showing hints on output {}", selected_output.name);
+        desktop_windows
+            .retain(|dw| output::output_for_window(outputs, dw) == Some(selected_output));
+    }
+
+    // Windows keep the hints they had last time if possible, so they can be selected by habit.
+    // Only the hints shown now are remembered, as they might be taken from windows not shown.
+    let hint_memory = hint_memory.for_chars(&app_config.hint_chars);
+    let window_ids: Vec<i64> = desktop_windows.iter().map(|dw| dw.id).collect();
+    let hints = utils::assign_hints(&window_ids, &app_config.hint_chars, hint_memory)
+        .context("Couldn't get hints")?;
+    *hint_memory = window_ids.into_iter().zip(hints.iter().cloned()).collect();
+
+    // Lay out all labels first as their boxes might all need to be the same size.
+    let mut labels = vec![];
+    for (desktop_window, hint) in desktop_windows.iter().zip(hints) {
         // We need to estimate the font size before rendering because we want the window to only be
         // the size of the font.
-        let hint = utils::get_next_hint(
-            render_windows.keys().collect(),
-            &app_config.hint_chars,
-            desktop_windows.len(),
-        )
-        .context("Couldn't get next hint")?;
-
-        // Figure out how large the window actually needs to be.
-        let text_extents = utils::extents_for_text(
+        // Figure out how large the window actually needs to be. Window metadata in the label is
+        // shortened to fit into the window.
+        let fonts = app_config.label_fonts(
+            dpi_for(output::output_for_window(outputs, desktop_window)),
+            f64::from(desktop_window.size.1),
+        );
+        let anchor = desktop_window.anchor_rect(app_config.anchor);
+        let max_label_width = utils::max_label_width(&app_config, &fonts.hint, anchor.2);
+        let (mut label, mut text_extents) = utils::layout_label(
+            &app_config,
+            &fonts,
+            &app_config.label,
             &hint,
-            &app_config.font.font_family,
-            app_config.font.font_size,
+            desktop_window,
+            max_label_width,
         )
-        .context("Couldn't create extents for text")?;
-        let (width, height, margin_width, margin_height) = if app_config.fill {
-            (
-                desktop_window.size.0 as u16,
-                desktop_window.size.1 as u16,
-                (f64::from(desktop_window.size.0) - text_extents.width) / 2.0,
-                (f64::from(desktop_window.size.1) - text_extents.height) / 2.0,
-            )
+        .context("Couldn't create extents for label")?;
+
+        // Icons are as high as the text and get drawn left of it, so we'll make some room for
+        // them by pretending the text extends further to the left.
+        let icon_size = text_extents.height.round();
+        let icon = if app_config.icons {
+            utils::get_window_icon(conn, desktop_window, icon_size as u32)
         } else {
-            let margin_factor = 1.0 + 0.2;
-            (
-                (text_extents.width * margin_factor).round() as u16,
-                (text_extents.height * margin_factor).round() as u16,
-                ((text_extents.width * margin_factor) - text_extents.width) / 2.0,
-                ((text_extents.height * margin_factor) - text_extents.height) / 2.0,
+            None
+        };
+        let icon_space = if icon.is_some() {
+            icon_size * 1.25
+        } else {
+            0.0
+        };
+        let layout_with_icon = |template| -> Result<_> {
+            let (label, mut text_extents) = utils::layout_label(
+                &app_config,
+                &fonts,
+                template,
+                &hint,
+                desktop_window,
+                max_label_width - icon_space,
             )
+            .context("Couldn't create extents for label")?;
+            text_extents.x_bearing -= icon_space;
+            text_extents.width += icon_space;
+            Ok((label, text_extents))
         };
+        if icon.is_some() {
+            let (icon_label, icon_text_extents) = layout_with_icon(&app_config.label)?;
+            label = icon_label;
+            text_extents = icon_text_extents;
+        }
+
+        // Only lay out the toggled label if it can actually be switched to.
+        let alt_label = if app_config.title_keys.is_empty() {
+            None
+        } else {
+            Some(layout_with_icon(&app_config.label.toggled())?)
+        };
+
+        labels.push((
+            desktop_window,
+            hint,
+            label,
+            text_extents,
+            icon.map(|icon| (icon, icon_size)),
+            alt_label,
+            fonts,
+        ));
+    }
+
+    // Uniform boxes are all as large as the largest one.
+    let uniform_size = labels
+        .iter()
+        .map(|(_, _, _, text_extents, _, _, fonts)| {
+            utils::box_size(&app_config, &fonts.hint, text_extents)
+        })
+        .fold((0.0, 0.0), |a: (f64, f64), b| (a.0.max(b.0), a.1.max(b.1)));
+
+    // The dim layer covers the whole screen and has to be mapped before the labels so that it
+    // ends up below them.
+    let dim_window = match app_config.dim {
+        Some(dim_color) => {
+            let (xcb_window_id, cairo_context) = overlays.create(
+                &screen,
+                (0, 0, screen.width_in_pixels(), screen.height_in_pixels()),
+                dim_color.3,
+                false,
+            )?;
+            if app_config.dim_except_current {
+                if let Some(current) = desktop_windows.iter().find(|dw| dw.is_focused) {
+                    utils::cut_out_rect(
+                        conn,
+                        xcb_window_id,
+                        (
+                            current.pos.0 as i16,
+                            current.pos.1 as i16,
+                            current.size.0 as u16,
+                            current.size.1 as u16,
+                        ),
+                    );
+                }
+            }
+            xcb::map_window(conn, xcb_window_id);
+            conn.flush();
+            Some((cairo_context, dim_color))
+        }
+        None => None,
+    };
+
+    // Frames outline the windows whose hints still match while typing. Like the dim layer, they
+    // have to be created before the labels to stay below them.
+    let mut frame_windows = HashMap::new();
+    if app_config.frame_width > 0 {
+        let frame_width = app_config.frame_width;
+        for (desktop_window, hint, ..) in &labels {
+            let (width, height) = (desktop_window.size.0 as u16, desktop_window.size.1 as u16);
+            let (xcb_window_id, cairo_context) = overlays.create(
+                &screen,
+                (
+                    desktop_window.pos.0 as i16,
+                    desktop_window.pos.1 as i16,
+                    width,
+                    height,
+                ),
+                app_config.frame_color.3,
+                false,
+            )?;
+            utils::cut_out_rect(
+                conn,
+                xcb_window_id,
+                (
+                    frame_width as i16,
+                    frame_width as i16,
+                    width.saturating_sub(2 * frame_width),
+                    height.saturating_sub(2 * frame_width),
+                ),
+            );
+            frame_windows.insert(hint.clone(), (xcb_window_id, cairo_context));
+        }
+    }
+
+    // Assemble RenderWindows from DesktopWindows. Labels that are already placed and their leader
+    // lines take up room for the labels placed after them.
+    let mut render_windows: HashMap<String, RenderWindow> = HashMap::new();
+    let mut taken = vec![];
+    for (desktop_window, hint, label, text_extents, icon, alt_label, fonts) in labels {
+        let anchor = desktop_window.anchor_rect(app_config.anchor);
+        let (box_width, box_height) = if app_config.fill {
+            (f64::from(anchor.2), f64::from(anchor.3))
+        } else if app_config.uniform {
+            uniform_size
+        } else {
+            utils::box_size(&app_config, &fonts.hint, &text_extents)
+        };
+        let (width, height, margin_width, margin_height) = (
+            box_width.round() as u16,
+            box_height.round() as u16,
+            (box_width - text_extents.width) / 2.0,
+            (box_height - text_extents.height) / 2.0,
+        );
 
         // Due to the way cairo lays out text, we'll have to calculate the actual coordinates to
         // put the cursor. See:
@@ -110,123 +826,187 @@ fn main() -> Result<()> {
             text_extents.height + margin_height - (text_extents.height + text_extents.y_bearing),
         );
 
+        let alt_label = alt_label.map(|(alt_label, alt_text_extents)| {
+            let alt_box_width = if app_config.fill || app_config.uniform {
+                box_width
+            } else {
+                utils::box_size(&app_config, &fonts.hint, &alt_text_extents).0
+            };
+            let alt_margin_width = (alt_box_width - alt_text_extents.width) / 2.0;
+            (
+                alt_label,
+                alt_box_width.round() as i32 - i32::from(width),
+                alt_margin_width - margin_width,
+            )
+        });
+
+        let icon = icon.map(|(icon, icon_size)| {
+            let icon_pos = (
+                draw_pos.0 + text_extents.x_bearing,
+                draw_pos.1 + text_extents.y_bearing,
+            );
+            (icon, (icon_pos.0, icon_pos.1, icon_size, icon_size))
+        });
+
         debug!(
             "Spawning RenderWindow for this DesktopWindow: {:?}",
             desktop_window
         );
 
         let x_offset = app_config.offset.x;
-        let mut x = match app_config.horizontal_align {
-            args::HorizontalAlign::Left => (desktop_window.pos.0 + x_offset) as i16,
+        let x = match app_config.horizontal_align {
+            args::HorizontalAlign::Left => (anchor.0 + x_offset) as i16,
             args::HorizontalAlign::Center => {
-                (desktop_window.pos.0 + desktop_window.size.0 / 2 - i32::from(width) / 2) as i16
+                (anchor.0 + anchor.2 / 2 - i32::from(width) / 2) as i16
             }
             args::HorizontalAlign::Right => {
-                (desktop_window.pos.0 + desktop_window.size.0 - i32::from(width) - x_offset) as i16
+                (anchor.0 + anchor.2 - i32::from(width) - x_offset) as i16
             }
         };
 
         let y_offset = app_config.offset.y;
         let y = match app_config.vertical_align {
-            args::VerticalAlign::Top => (desktop_window.pos.1 + y_offset) as i16,
-            args::VerticalAlign::Center => {
-                (desktop_window.pos.1 + desktop_window.size.1 / 2 - i32::from(height) / 2) as i16
-            }
+            args::VerticalAlign::Top => (anchor.1 + y_offset) as i16,
+            args::VerticalAlign::Center => (anchor.1 + anchor.3 / 2 - i32::from(height) / 2) as i16,
             args::VerticalAlign::Bottom => {
-                (desktop_window.pos.1 + desktop_window.size.1 - i32::from(height) - y_offset) as i16
+                (anchor.1 + anchor.3 - i32::from(height) - y_offset) as i16
             }
         };
 
-        // If this is overlapping then we'll nudge the new RenderWindow a little bit out of the
-        // way.
-        let mut overlaps = utils::find_overlaps(
-            render_windows.values().collect(),
-            (x.into(), y.into(), width.into(), height.into()),
+        // Overlapping labels get moved elsewhere in or near their window, but not off the monitor
+        // their window is on. Filled labels have to stay where their window is.
+        let preferred = (
+            x.into(),
+            y.into(),
+            (width + app_config.shadow).into(),
+            (height + app_config.shadow).into(),
         );
-        while !overlaps.is_empty() {
-            x += overlaps.pop().unwrap().2 as i16;
-            overlaps = utils::find_overlaps(
-                render_windows.values().collect(),
-                (x.into(), y.into(), width.into(), height.into()),
-            );
-        }
-
-        let xcb_window_id = conn.generate_id();
+        let output_rect = output::output_for_window(outputs, desktop_window).map(|o| o.rect);
+        let placement = if app_config.fill {
+            placement::Placement {
+                rect: preferred,
+                leader: false,
+            }
+        } else {
+            placement::place(preferred, anchor, output_rect, &taken)
+        };
+        let rect = placement.rect;
 
-        // Create the actual window.
-        xcb::create_window(
-            &conn,
-            xcb::COPY_FROM_PARENT as u8,
-            xcb_window_id,
-            screen.root(),
-            x,
-            y,
-            width,
-            height,
-            0,
-            xcb::WINDOW_CLASS_INPUT_OUTPUT as u16,
-            screen.root_visual(),
-            &values,
-        );
+        // Labels placed away from their window get a line leading to it, below the label.
+        let segments = if placement.leader {
+            placement::leader_segments(rect, anchor)
+        } else {
+            vec![]
+        };
 
-        xcb::map_window(&conn, xcb_window_id);
-
-        // Set transparency.
-        let opacity_atom = xcb::intern_atom(&conn, false, "_NET_WM_WINDOW_OPACITY")
-            .get_reply()
-            .context("Couldn't create atom _NET_WM_WINDOW_OPACITY")?
-            .atom();
-        let opacity = (0xFFFFFFFFu64 as f64 * app_config.bg_color.3) as u64;
-        xcb::change_property(
-            &conn,
-            xcb::PROP_MODE_REPLACE as u8,
-            xcb_window_id,
-            opacity_atom,
-            xcb::ATOM_CARDINAL,
-            32,
-            &[opacity],
-        );
+        // Labels can get wider later on when toggling to their alternate label.
+        let grown = alt_label
+            .as_ref()
+            .map_or(0, |(_, width_change, _)| (*width_change).max(0));
+        taken.push((
+            rect.0 - utils::align_shift(app_config.horizontal_align, grown),
+            rect.1,
+            rect.2 + grown,
+            rect.3,
+        ));
+        taken.extend(&segments);
+        let leader = if let Some(bounds) = placement::bounding_rect(&segments) {
+            let (xcb_window_id, cairo_context) = overlays.create(
+                &screen,
+                (
+                    bounds.0 as i16,
+                    bounds.1 as i16,
+                    bounds.2 as u16,
+                    bounds.3 as u16,
+                ),
+                app_config.frame_color.3,
+                false,
+            )?;
+            let relative: Vec<_> = segments
+                .iter()
+                .map(|s| {
+                    (
+                        (s.0 - bounds.0) as i16,
+                        (s.1 - bounds.1) as i16,
+                        s.2 as u16,
+                        s.3 as u16,
+                    )
+                })
+                .collect();
+            utils::shape_window(conn, xcb_window_id, &relative);
+            xcb::map_window(conn, xcb_window_id);
+            Some((xcb_window_id, cairo_context))
+        } else {
+            None
+        };
 
+        // Labels with an alpha channel draw their own transparency and shadow.
+        let (xcb_window_id, cairo_context) = overlays.create(
+            &screen,
+            (rect.0 as i16, rect.1 as i16, rect.2 as u16, rect.3 as u16),
+            app_config.label_opacity(),
+            app_config.transparent_labels(),
+        )?;
+        xcb::map_window(conn, xcb_window_id);
         conn.flush();
 
-        let mut visual =
-            utils::find_visual(&conn, screen.root_visual()).context("Couldn't find visual")?;
-        let cairo_xcb_conn = unsafe {
-            cairo::XCBConnection::from_raw_none(
-                conn.get_raw_conn() as *mut cairo_sys::xcb_connection_t
-            )
-        };
-        let cairo_xcb_drawable = cairo::XCBDrawable(xcb_window_id);
-        let raw_visualtype = &mut visual.base as *mut xcb::ffi::xcb_visualtype_t;
-        let cairo_xcb_visual = unsafe {
-            cairo::XCBVisualType::from_raw_none(raw_visualtype as *mut cairo_sys::xcb_visualtype_t)
-        };
-        let surface = cairo::XCBSurface::create(
-            &cairo_xcb_conn,
-            &cairo_xcb_drawable,
-            &cairo_xcb_visual,
-            width.into(),
-            height.into(),
-        )
-        .context("Couldn't create Cairo Surface")?;
-        let cairo_context =
-            cairo::Context::new(&surface).context("Couldn't create Cairo Context")?;
-
         let render_window = RenderWindow {
             desktop_window,
+            xcb_window_id,
             cairo_context,
+            label,
+            icon,
+            fonts,
             draw_pos,
-            rect: (x.into(), y.into(), width.into(), height.into()),
+            rect,
+            full_rect: rect,
+            visible: true,
+            alt_label,
+            leader,
         };
 
         render_windows.insert(hint, render_window);
     }
 
+    // The current action and the query in search mode are shown in their own window in the middle
+    // of the screen.
+    let query_window = {
+        let query_output = desktop_windows
+            .iter()
+            .find(|dw| dw.is_focused)
+            .and_then(|dw| output::output_for_window(outputs, dw))
+            .unwrap_or(&outputs[0]);
+        let output_rect = query_output.rect;
+        let font = app_config
+            .label_fonts(dpi_for(Some(query_output)), f64::from(output_rect.3))
+            .hint;
+        let text_extents =
+            utils::extents_for_text("/Ay", &font).context("Couldn't create extents for text")?;
+        let (_, box_height) = utils::box_size(&app_config, &font, &text_extents);
+        let width = (output_rect.2 / 3) as u16;
+        let height = box_height.round() as u16;
+        let margin_height = (box_height - text_extents.height) / 2.0;
+        let draw_pos = (
+            margin_height - text_extents.x_bearing,
+            text_extents.height + margin_height - (text_extents.height + text_extents.y_bearing),
+        );
+        let x = (output_rect.0 + (output_rect.2 - i32::from(width)) / 2) as i16;
+        let y = (output_rect.1 + (output_rect.3 - i32::from(height)) / 2) as i16;
+        let (xcb_window_id, cairo_context) =
+            overlays.create(&screen, (x, y, width, height), app_config.bg_color.3, false)?;
+        QueryWindow {
+            xcb_window_id,
+            cairo_context,
+            font,
+            draw_pos,
+        }
+    };
+
     // Receive keyboard events.
-    utils::snatch_keyboard(&conn, &screen, Duration::from_secs(1))?;
+    utils::snatch_keyboard(conn, &screen, Duration::from_secs(1))?;
 
     // Receive mouse events.
-    utils::snatch_mouse(&conn, &screen, Duration::from_secs(1))?;
+    utils::snatch_mouse(conn, &screen, Duration::from_secs(1))?;
 
     // Since we might have lots of windows on the desktop, it might be required
     // to enter a sequence in order to get to the correct window.
@@ -234,6 +1014,11 @@ fn main() -> Result<()> {
     let mut pressed_keys = String::default();
     let mut sequence = utils::Sequence::new(None);
 
+    // In search mode, typed text is matched against window titles and classes instead.
+    let mut query = app_config.search.then(String::new);
+    let mut action = app_config.action;
+    update_prompt(conn, &app_config, &query_window, action, query.as_deref())?;
+
     let mut closed = false;
     while !closed {
         let event = conn.wait_for_event();
@@ -245,43 +1030,240 @@ fn main() -> Result<()> {
                 let r = event.response_type();
                 match r {
                     xcb::EXPOSE => {
+                        if let Some((cairo_context, dim_color)) = &dim_window {
+                            utils::draw_fill(cairo_context, *dim_color)?;
+                            conn.flush();
+                        }
+                        let leaders = render_windows.values().filter_map(|rw| rw.leader.as_ref());
+                        for (_, cairo_context) in frame_windows.values().chain(leaders) {
+                            utils::draw_fill(cairo_context, app_config.frame_color)?;
+                            conn.flush();
+                        }
                         for (hint, rw) in &render_windows {
                             utils::draw_hint_text(rw, &app_config, hint, &pressed_keys)
                                 .context("Couldn't draw hint text")?;
                             conn.flush();
                         }
+                        if action != args::Action::Focus || query.is_some() {
+                            utils::draw_prompt(
+                                &query_window,
+                                &app_config,
+                                action,
+                                query.as_deref(),
+                            )
+                            .context("Couldn't draw prompt")?;
+                            conn.flush();
+                        }
                     }
                     xcb::BUTTON_PRESS => {
-                        closed = true;
+                        // Left-click selects with the current action, middle-click with the
+                        // alternate one and right-click cancels. Other buttons (eg scrolling)
+                        // are ignored.
+                        let button_press: &xcb::ButtonPressEvent =
+                            unsafe { xcb::cast_event(&event) };
+                        let action = match button_press.detail() {
+                            1 => action,
+                            2 => app_config.middle_click_action,
+                            3 => {
+                                closed = true;
+                                continue;
+                            }
+                            _ => continue,
+                        };
+                        let point = (
+                            i32::from(button_press.root_x()),
+                            i32::from(button_press.root_y()),
+                        );
+                        if let Some(hint) = utils::find_hint_at(&render_windows, point) {
+                            info!("Clicked window with hint '{}', selecting", hint);
+                            select_window(
+                                &app_config,
+                                out,
+                                action,
+                                render_windows[hint].desktop_window,
+                            )?;
+                            closed = true;
+                        }
                     }
                     xcb::KEY_RELEASE => {
-                        let ksym = utils::get_pressed_symbol(&conn, &event);
-                        let kstr = utils::convert_to_string(ksym)
-                            .context("Couldn't convert ksym to string")?;
-                        sequence.remove(kstr);
+                        let key = keyboard.lookup(&event);
+                        sequence.remove(&key.name);
                     }
                     xcb::KEY_PRESS => {
-                        let ksym = utils::get_pressed_symbol(&conn, &event);
-                        let kstr = utils::convert_to_string(ksym)
-                            .context("Couldn't convert ksym to string")?;
+                        let key = keyboard.lookup(&event);
 
-                        sequence.push(kstr.to_owned());
+                        sequence.push(key.name.clone());
+                        let bound = |keys: &[utils::Sequence]| keys.contains(&sequence);
 
-                        if app_config.hint_chars.contains(kstr) {
-                            info!("Adding '{}' to key sequence", kstr);
-                            pressed_keys.push_str(kstr);
-                        } else {
-                            warn!("Pressed key '{}' is not a valid hint characters", kstr);
+                        if key.keysym == xkb::KEY_Escape || bound(&app_config.exit_keys) {
+                            info!("{:?} is exit sequence", sequence);
+                            closed = true;
+                            continue;
                         }
 
-                        info!("Current key sequence: '{}'", pressed_keys);
+                        if bound(&app_config.action_keys) {
+                            action = action.next();
+                            info!("Switched to action '{}'", action.name());
+                            update_prompt(
+                                conn,
+                                &app_config,
+                                &query_window,
+                                action,
+                                query.as_deref(),
+                            )?;
+                            continue;
+                        }
 
-                        if ksym == xkb::KEY_Escape || app_config.exit_keys.contains(&sequence) {
-                            info!("{:?} is exit sequence", sequence);
-                            closed = true;
+                        if bound(&app_config.title_keys) {
+                            toggle_labels(conn, &app_config, &mut render_windows)?;
+                            if query.is_some() {
+                                for (hint, rw) in &render_windows {
+                                    utils::draw_hint_text(rw, &app_config, hint, &pressed_keys)
+                                        .context("Couldn't draw hint text")?;
+                                }
+                                conn.flush();
+                            } else {
+                                update_labels(
+                                    conn,
+                                    &app_config,
+                                    &mut render_windows,
+                                    &frame_windows,
+                                    &pressed_keys,
+                                )?;
+                            }
+                            continue;
+                        }
+
+                        // Modifiers on their own and keys pressed together with Control, Alt or
+                        // Super are only used in key bindings.
+                        if utils::is_modifier(key.keysym)
+                            || (sequence.is_started() && key.text.is_empty())
+                        {
+                            continue;
+                        }
+
+                        // Switching modes starts over with all labels visible. Once something
+                        // has been searched for, search keys that type text are part of the query
+                        // so that eg `/` can be searched for too.
+                        let typing = query
+                            .as_ref()
+                            .is_some_and(|query| !query.is_empty() && !key.text.is_empty());
+                        if bound(&app_config.search_keys) && !typing {
+                            query = match query {
+                                Some(_) => None,
+                                None => Some(String::new()),
+                            };
+                            pressed_keys.clear();
+                            update_labels(
+                                conn,
+                                &app_config,
+                                &mut render_windows,
+                                &frame_windows,
+                                &pressed_keys,
+                            )?;
+                            update_prompt(
+                                conn,
+                                &app_config,
+                                &query_window,
+                                action,
+                                query.as_deref(),
+                            )?;
+                            continue;
+                        }
+
+                        if let Some(query) = &mut query {
+                            let confirmed = bound(&app_config.confirm_keys);
+                            if bound(&app_config.backspace_keys) {
+                                query.pop();
+                            } else if bound(&app_config.clear_keys) {
+                                query.clear();
+                            } else if !confirmed {
+                                query.push_str(&key.text);
+                            }
+                            info!("Current query: '{}'", query);
+
+                            // Order matches by score first and hint second so that confirming
+                            // predictably picks the best one.
+                            let mut matches: Vec<_> = render_windows
+                                .iter()
+                                .filter_map(|(hint, rw)| {
+                                    utils::window_score(rw.desktop_window, query)
+                                        .map(|score| (score, hint, rw))
+                                })
+                                .collect();
+                            matches.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+                            if (confirmed || (matches.len() == 1 && !query.is_empty()))
+                                && !matches.is_empty()
+                            {
+                                info!("Found matching window, selecting");
+                                select_window(
+                                    &app_config,
+                                    out,
+                                    action,
+                                    matches[0].2.desktop_window,
+                                )?;
+                                closed = true;
+                                continue;
+                            }
+
+                            let matching: Vec<String> =
+                                matches.into_iter().map(|m| m.1.clone()).collect();
+                            for (hint, rw) in render_windows.iter_mut() {
+                                show_label(conn, rw, matching.contains(hint));
+                            }
+                            update_prompt(conn, &app_config, &query_window, action, Some(query))?;
+                            continue;
+                        }
+
+                        // Correct typos by removing the last typed key or start over.
+                        if bound(&app_config.backspace_keys) || bound(&app_config.clear_keys) {
+                            if bound(&app_config.backspace_keys) {
+                                pressed_keys.pop();
+                            } else {
+                                pressed_keys.clear();
+                            }
+                            info!("Current key sequence: '{}'", pressed_keys);
+                            update_labels(
+                                conn,
+                                &app_config,
+                                &mut render_windows,
+                                &frame_windows,
+                                &pressed_keys,
+                            )?;
+                            continue;
+                        }
+
+                        // Confirming picks the first window whose hint still matches.
+                        if bound(&app_config.confirm_keys) {
+                            let best = render_windows
+                                .iter()
+                                .filter(|(hint, _)| {
+                                    !pressed_keys.is_empty() && hint.starts_with(&pressed_keys)
+                                })
+                                .min_by_key(|(hint, _)| *hint);
+                            if let Some((_, rw)) = best {
+                                info!("Found matching window, selecting");
+                                select_window(&app_config, out, action, rw.desktop_window)?;
+                                closed = true;
+                            }
                             continue;
                         }
 
+                        // Hint keys given by position match regardless of the active layout.
+                        let text = match hint_keys.get(&key.keycode) {
+                            Some(c) => c.to_string(),
+                            None => key.text,
+                        };
+                        if !text.is_empty() && app_config.hint_chars.contains(&text) {
+                            info!("Adding '{}' to key sequence", text);
+                            pressed_keys.push_str(&text);
+                        } else {
+                            warn!("Pressed key '{}' is not a valid hint characters", key.name);
+                        }
+
+                        info!("Current key sequence: '{}'", pressed_keys);
+
                         // Attempt to match the current sequence of keys as a string to the window
                         // hints shown.
                         // If there is an exact match, we're done. We'll then focus the window
@@ -290,30 +1272,25 @@ fn main() -> Result<()> {
                         // is not then we will also just exit and focus no new window.
                         // If there still is a chance we might find a window then we'll just
                         // keep going for now.
-                        if sequence.is_started() {
-                            utils::remove_last_key(&mut pressed_keys, kstr);
-                        } else if let Some(rw) = &render_windows.get(&pressed_keys) {
-                            info!("Found matching window, focusing");
-                            if app_config.print_only {
-                                println!("0x{:x}", rw.desktop_window.x_window_id.unwrap_or(0));
-                            } else {
-                                wm::focus_window(rw.desktop_window)
-                                    .context("Couldn't focus window")?;
-                            }
+                        if let Some(rw) = &render_windows.get(&pressed_keys) {
+                            info!("Found matching window, selecting");
+                            select_window(&app_config, out, action, rw.desktop_window)?;
                             closed = true;
                         } else if !pressed_keys.is_empty()
                             && render_windows.keys().any(|k| k.starts_with(&pressed_keys))
                         {
-                            for (hint, rw) in &render_windows {
-                                utils::draw_hint_text(rw, &app_config, hint, &pressed_keys)
-                                    .context("Couldn't draw hint text")?;
-                                conn.flush();
-                            }
+                            update_labels(
+                                conn,
+                                &app_config,
+                                &mut render_windows,
+                                &frame_windows,
+                                &pressed_keys,
+                            )?;
                             continue;
                         } else {
                             warn!("No more matches possible with current key sequence");
                             closed = app_config.exit_keys.is_empty();
-                            utils::remove_last_key(&mut pressed_keys, kstr);
+                            utils::remove_last_key(&mut pressed_keys, &text);
                         }
                     }
                     _ => {}
@@ -325,6 +1302,97 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+#[cfg(any(feature = "i3", feature = "add_some_other_wm_here"))]
+fn main() -> Result<()> {
+    pretty_env_logger::init();
+    let app_config = args::parse_args()?;
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if app_config.daemon {
+        let (conn, screen_num) = xcb::Connection::connect(None).context("No Xorg connection")?;
+        let window_cache: SharedWindowCache =
+            Arc::new(Mutex::new(WindowCache::new(&app_config.hint_chars)));
+        wm::watch_windows(window_cache.clone());
+        // Resizing windows with the mouse or changing layouts doesn't cause any window manager
+        // events, so keep an eye on the windows themselves.
+        let geometry_cache = window_cache.clone();
+        utils::watch_window_geometry(move || {
+            if let Err(e) = wm::refresh_windows(&geometry_cache) {
+                warn!("Couldn't refresh windows: {:#}", e);
+            }
+        })
+        .context("Couldn't watch windows")?;
+        // The keyboard, X resources and outputs are only queried again once they changed.
+        let (setup_tx, setup_changes) = mpsc::channel();
+        utils::watch_setup(move |change| {
+            let _ = setup_tx.send(change);
+        })
+        .context("Couldn't watch the X server for changes")?;
+        let screen = conn
+            .get_setup()
+            .roots()
+            .nth(screen_num as usize)
+            .context("Couldn't get screen")?;
+        let mut x_state = XState::new(&conn, &screen)?;
+        return daemon::serve(&args, |app_config, out| {
+            // Start from scratch every time, without events left over from the last run.
+            while conn.poll_for_event().is_some() {}
+            let changes: HashSet<_> = setup_changes.try_iter().collect();
+            for change in changes {
+                if let Err(e) = x_state.update(&conn, &screen, change) {
+                    warn!("{:#}", e);
+                }
+            }
+            let (cached_windows, mut hint_memory) = {
+                let cache = window_cache.lock().unwrap();
+                (cache.windows.clone(), cache.hints.clone())
+            };
+            let desktop_windows = match cached_windows {
+                Some(desktop_windows) => desktop_windows,
+                None => wm::get_windows().context("Couldn't get desktop windows")?,
+            };
+            let result = show_hints(
+                &conn,
+                screen_num,
+                app_config,
+                desktop_windows,
+                &mut x_state,
+                &mut hint_memory,
+                out,
+            );
+            window_cache.lock().unwrap().remember(hint_memory);
+            xcb::ungrab_keyboard(&conn, xcb::CURRENT_TIME);
+            xcb::ungrab_pointer(&conn, xcb::CURRENT_TIME);
+            conn.flush();
+            result
+        });
+    }
+
+    // Let a running daemon show the hints as it's much faster.
+    if daemon::request(&args)? {
+        return Ok(());
+    }
+
+    // Get the windows from each specific window manager implementation.
+    let desktop_windows = wm::get_windows().context("Couldn't get desktop windows")?;
+    let (conn, screen_num) = xcb::Connection::connect(None).context("No Xorg connection")?;
+    let screen = conn
+        .get_setup()
+        .roots()
+        .nth(screen_num as usize)
+        .context("Couldn't get screen")?;
+    let mut x_state = XState::new(&conn, &screen)?;
+    show_hints(
+        &conn,
+        screen_num,
+        app_config,
+        desktop_windows,
+        &mut x_state,
+        &mut HintMemory::default(),
+        &mut io::stdout(),
+    )
+}
+
 #[cfg(not(any(feature = "i3", feature = "add_some_other_wm_here")))]
 fn main() -> Result<()> {
     eprintln!(
@@ -335,3 +1403,52 @@ Currently supported:
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(id: i64, x: i32) -> DesktopWindow {
+        DesktopWindow {
+            id,
+            x_window_id: None,
+            pos: (x, 0),
+            size: (100, 100),
+            is_focused: false,
+            title: String::new(),
+            class: String::new(),
+            output: None,
+            titlebar: None,
+            content: None,
+        }
+    }
+
+    #[test]
+    fn test_window_cache_remember() {
+        let mut cache = WindowCache::new("sd");
+        cache.update(vec![window(1, 0), window(2, 100), window(3, 200)]);
+        let before = cache.hints.by_chars["sd"].clone();
+        assert_eq!(before.len(), 3);
+
+        // The hint shown for a window wins over the one another window had.
+        let mut hint_memory = cache.hints.clone();
+        *hint_memory.for_chars("sd") = HashMap::from([(3, before[&1].clone())]);
+        cache.remember(hint_memory);
+        let hints = &cache.hints.by_chars["sd"];
+        assert_eq!(hints[&3], before[&1]);
+        assert_eq!(hints[&2], before[&2]);
+        assert!(hints[&1] != hints[&3] && hints[&1] != hints[&2]);
+
+        // Hints made of other characters are kept up to date as well from then on.
+        let mut hint_memory = cache.hints.clone();
+        *hint_memory.for_chars("jk") = HashMap::from([(2, "kj".to_string())]);
+        cache.remember(hint_memory);
+        cache.update(vec![window(2, 100), window(3, 200), window(4, 300)]);
+        let hints = &cache.hints.by_chars["jk"];
+        assert_eq!(hints.len(), 3);
+        assert_eq!(hints[&2], "kj");
+        assert!(hints
+            .values()
+            .all(|hint| hint.chars().all(|c| "jk".contains(c))));
+    }
+}
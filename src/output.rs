@@ -0,0 +1,202 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+use crate::DesktopWindow;
+
+/// A monitor as reported by RandR.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub name: String,
+    /// Position and size in the format (x, y, w, h).
+    pub rect: (i32, i32, i32, i32),
+    /// Physical size in millimeters, 0 if unknown.
+    pub mm_size: (u32, u32),
+}
+
+impl Output {
+    /// Resolution of this output in dots per inch, if it reports a plausible physical size.
+    /// Projectors and TVs tend to report bogus sizes or none at all.
+    pub fn dpi(&self) -> Option<f64> {
+        if self.mm_size.1 == 0 {
+            return None;
+        }
+        let dpi = f64::from(self.rect.3) * 25.4 / f64::from(self.mm_size.1);
+        Some(dpi).filter(|dpi| (48.0..=600.0).contains(dpi))
+    }
+}
+
+/// Get `Xft.dpi` from an X resource database as returned by `xrdb -query`.
+pub fn parse_xft_dpi(resources: &str) -> Option<f64> {
+    resources
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "Xft.dpi")
+        .and_then(|(_, value)| value.trim().parse::<f64>().ok())
+        .filter(|dpi| *dpi > 0.0)
+}
+
+/// Query all active outputs of `screen`. Falls back to the whole screen as single output if RandR
+/// doesn't report any.
+pub fn get_outputs(conn: &xcb::Connection, screen: &xcb::Screen) -> Vec<Output> {
+    match query_outputs(conn, screen) {
+        Ok(outputs) if !outputs.is_empty() => outputs,
+        result => {
+            if let Err(e) = result {
+                warn!("Couldn't query outputs: {:#}", e);
+            }
+            vec![Output {
+                name: "screen".to_string(),
+                rect: (
+                    0,
+                    0,
+                    i32::from(screen.width_in_pixels()),
+                    i32::from(screen.height_in_pixels()),
+                ),
+                mm_size: (
+                    u32::from(screen.width_in_millimeters()),
+                    u32::from(screen.height_in_millimeters()),
+                ),
+            }]
+        }
+    }
+}
+
+fn query_outputs(conn: &xcb::Connection, screen: &xcb::Screen) -> Result<Vec<Output>> {
+    let resources = xcb::randr::get_screen_resources_current(conn, screen.root())
+        .get_reply()
+        .context("Couldn't get screen resources")?;
+    let timestamp = resources.config_timestamp();
+    let mut outputs = vec![];
+    for output in resources.outputs() {
+        let info = xcb::randr::get_output_info(conn, *output, timestamp)
+            .get_reply()
+            .context("Couldn't get output info")?;
+        if info.connection() != xcb::randr::CONNECTION_CONNECTED as u8 || info.crtc() == 0 {
+            continue;
+        }
+        let crtc = xcb::randr::get_crtc_info(conn, info.crtc(), timestamp)
+            .get_reply()
+            .context("Couldn't get CRTC info")?;
+        let output = Output {
+            name: String::from_utf8_lossy(info.name()).into_owned(),
+            rect: (
+                i32::from(crtc.x()),
+                i32::from(crtc.y()),
+                i32::from(crtc.width()),
+                i32::from(crtc.height()),
+            ),
+            mm_size: (info.mm_width(), info.mm_height()),
+        };
+        debug!("Found output: {:?}", output);
+        outputs.push(output);
+    }
+    Ok(outputs)
+}
+
+/// Area in which `r1` and `r2` overlap.
+fn overlap_area(r1: (i32, i32, i32, i32), r2: (i32, i32, i32, i32)) -> i64 {
+    let width = (r1.0 + r1.2).min(r2.0 + r2.2) - r1.0.max(r2.0);
+    let height = (r1.1 + r1.3).min(r2.1 + r2.3) - r1.1.max(r2.1);
+    i64::from(width.max(0)) * i64::from(height.max(0))
+}
+
+/// The output showing most of `rect`.
+pub fn output_for_rect(outputs: &[Output], rect: (i32, i32, i32, i32)) -> Option<&Output> {
+    outputs
+        .iter()
+        .filter(|output| overlap_area(output.rect, rect) > 0)
+        .max_by_key(|output| overlap_area(output.rect, rect))
+}
+
+/// The output `dw` is on. The output reported by the window manager is preferred, otherwise it's
+/// the one showing most of the window.
+pub fn output_for_window<'a>(outputs: &'a [Output], dw: &DesktopWindow) -> Option<&'a Output> {
+    dw.output
+        .as_ref()
+        .and_then(|name| outputs.iter().find(|output| output.name == *name))
+        .or_else(|| output_for_rect(outputs, dw.rect()))
+}
+
+/// The output containing `point`.
+pub fn output_at(outputs: &[Output], point: (i32, i32)) -> Option<&Output> {
+    output_for_rect(outputs, (point.0, point.1, 1, 1))
+}
+
+/// Move `rect` so it lies within `bounds` as far as possible. Rects larger than `bounds` stick to
+/// its top left corner.
+pub fn clamp(rect: (i32, i32, i32, i32), bounds: (i32, i32, i32, i32)) -> (i32, i32, i32, i32) {
+    let x = rect.0.min(bounds.0 + bounds.2 - rect.2).max(bounds.0);
+    let y = rect.1.min(bounds.1 + bounds.3 - rect.3).max(bounds.1);
+    (x, y, rect.2, rect.3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(name: &str, rect: (i32, i32, i32, i32)) -> Output {
+        Output {
+            name: name.to_string(),
+            rect,
+            mm_size: (0, 0),
+        }
+    }
+
+    #[test]
+    fn test_output_for_rect() {
+        let outputs = [
+            output("left", (0, 0, 1920, 1080)),
+            output("right", (1920, 0, 3840, 2160)),
+        ];
+        assert_eq!(
+            output_for_rect(&outputs, (1800, 0, 400, 400)).unwrap().name,
+            "right"
+        );
+        assert_eq!(
+            output_for_rect(&outputs, (1800, 0, 200, 400)).unwrap().name,
+            "left"
+        );
+        assert_eq!(output_at(&outputs, (1920, 10)).unwrap().name, "right");
+        assert!(output_at(&outputs, (100, 1500)).is_none());
+
+        let mut dw = DesktopWindow {
+            id: 1,
+            x_window_id: None,
+            pos: (1800, 0),
+            size: (400, 400),
+            is_focused: false,
+            title: String::new(),
+            class: String::new(),
+            output: Some("left".to_string()),
+            titlebar: None,
+            content: None,
+        };
+        assert_eq!(output_for_window(&outputs, &dw).unwrap().name, "left");
+        dw.output = Some("HDMI-1".to_string());
+        assert_eq!(output_for_window(&outputs, &dw).unwrap().name, "right");
+    }
+
+    #[test]
+    fn test_dpi() {
+        let mut laptop = output("eDP-1", (0, 0, 3840, 2160));
+        laptop.mm_size = (344, 194);
+        assert_eq!(laptop.dpi().unwrap().round(), 283.0);
+        laptop.mm_size = (16, 9);
+        assert!(laptop.dpi().is_none());
+
+        assert_eq!(
+            parse_xft_dpi("Xcursor.size: 24\nXft.dpi:\t144\n"),
+            Some(144.0)
+        );
+        assert_eq!(parse_xft_dpi("Xcursor.size: 24\n"), None);
+    }
+
+    #[test]
+    fn test_clamp() {
+        let bounds = (1920, 0, 1920, 1080);
+        assert_eq!(clamp((1900, 10, 100, 50), bounds), (1920, 10, 100, 50));
+        assert_eq!(clamp((3800, 1050, 100, 50), bounds), (3740, 1030, 100, 50));
+        assert_eq!(clamp((2000, 100, 100, 50), bounds), (2000, 100, 100, 50));
+        assert_eq!(clamp((2000, 100, 3000, 50), bounds), (1920, 100, 3000, 50));
+    }
+}
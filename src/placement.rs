@@ -0,0 +1,296 @@
+use std::iter;
+
+use crate::output;
+use crate::utils::intersects;
+
+/// A rect in the format (x, y, w, h).
+type Rect = (i32, i32, i32, i32);
+
+/// Thickness of the line leading from a label placed away from its window to that window.
+pub const LEADER_WIDTH: i32 = 2;
+
+/// How far around its window, in label sizes, a spot for a label is looked for first before trying
+/// the whole output.
+const NEIGHBOURHOOD: i32 = 4;
+
+/// Maximum number of positions `grid` returns, so that placing small labels on large outputs
+/// stays fast.
+const MAX_GRID_POSITIONS: usize = 1024;
+
+/// Where a label ended up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub rect: Rect,
+    /// Whether the label is away from its window and needs a leader line.
+    pub leader: bool,
+}
+
+/// Squared distance between the top left corners of `r1` and `r2`.
+fn distance(r1: Rect, r2: Rect) -> i64 {
+    let (dx, dy) = (i64::from(r1.0 - r2.0), i64::from(r1.1 - r2.1));
+    dx * dx + dy * dy
+}
+
+fn is_free(rect: Rect, taken: &[Rect]) -> bool {
+    !taken.iter().any(|other| intersects(*other, rect))
+}
+
+/// Whether the leader line from a label at `rect` to `window` stays clear of `taken`, apart from
+/// where it ends inside of the window.
+fn leader_is_free(rect: Rect, window: Rect, taken: &[Rect]) -> bool {
+    leader_segments(rect, window).into_iter().all(|segment| {
+        taken
+            .iter()
+            .filter_map(|other| intersection(*other, segment))
+            .all(|overlap| intersection(overlap, window) == Some(overlap))
+    })
+}
+
+/// The corners, edge centers and center of `window` for a box of `size`.
+fn anchors(size: (i32, i32), window: Rect) -> Vec<Rect> {
+    let xs = [
+        window.0,
+        window.0 + (window.2 - size.0) / 2,
+        window.0 + window.2 - size.0,
+    ];
+    let ys = [
+        window.1,
+        window.1 + (window.3 - size.1) / 2,
+        window.1 + window.3 - size.1,
+    ];
+    ys.iter()
+        .flat_map(|y| xs.iter().map(move |x| (*x, *y, size.0, size.1)))
+        .collect()
+}
+
+/// Positions of a box of `size` within `area` in steps of half its size. Steps get larger for
+/// large areas so that there are no more than `MAX_GRID_POSITIONS`.
+fn grid(size: (i32, i32), area: Rect) -> Vec<Rect> {
+    let free = ((area.2 - size.0).max(0), (area.3 - size.1).max(0));
+    let count = |step: (i32, i32)| ((free.0 / step.0 + 1) * (free.1 / step.1 + 1)) as usize;
+    let mut step = ((size.0 / 2).max(1), (size.1 / 2).max(1));
+    while count(step) > MAX_GRID_POSITIONS {
+        step = (step.0 * 2, step.1 * 2);
+    }
+    (area.1..=area.1 + area.3 - size.1)
+        .step_by(step.1 as usize)
+        .flat_map(|y| {
+            (area.0..=area.0 + area.2 - size.0)
+                .step_by(step.0 as usize)
+                .map(move |x| (x, y, size.0, size.1))
+        })
+        .collect()
+}
+
+/// The part of `r1` that is also in `r2`, if any.
+fn intersection(r1: Rect, r2: Rect) -> Option<Rect> {
+    let (x, y) = (r1.0.max(r2.0), r1.1.max(r2.1));
+    let (w, h) = (
+        (r1.0 + r1.2).min(r2.0 + r2.2) - x,
+        (r1.1 + r1.3).min(r2.1 + r2.3) - y,
+    );
+    (w > 0 && h > 0).then_some((x, y, w, h))
+}
+
+/// Find a spot for a label that doesn't overlap any of the `taken` rects, which are the rects of
+/// the other labels and their leader lines.
+///
+/// `preferred` is where the label goes according to the configured alignment. If that's taken,
+/// the anchors of `window` and then any other spot inside of it are tried, closest to the
+/// preferred one first. Failing that, the label goes to the closest free spot around the window or
+/// anywhere else on the output and gets a leader line, which mustn't cross anything taken either. Labels are kept within the output `bounds`
+/// throughout. If there's no room at all, the label stays at its preferred spot and overlaps.
+pub fn place(preferred: Rect, window: Rect, bounds: Option<Rect>, taken: &[Rect]) -> Placement {
+    let size = (preferred.2, preferred.3);
+    let fit = |rect| bounds.map_or(rect, |bounds| output::clamp(rect, bounds));
+    let by_distance = |mut rects: Vec<Rect>| {
+        rects.sort_by_key(|rect| distance(*rect, preferred));
+        rects
+    };
+
+    let near_window = iter::once(preferred)
+        .chain(by_distance(anchors(size, window)))
+        .chain(by_distance(grid(size, window)))
+        .map(fit);
+    for rect in near_window {
+        if is_free(rect, taken) {
+            return Placement {
+                rect,
+                leader: false,
+            };
+        }
+    }
+
+    if let Some(bounds) = bounds {
+        let neighbourhood = (
+            window.0 - NEIGHBOURHOOD * size.0,
+            window.1 - NEIGHBOURHOOD * size.1,
+            window.2 + 2 * NEIGHBOURHOOD * size.0,
+            window.3 + 2 * NEIGHBOURHOOD * size.1,
+        );
+        let areas = intersection(neighbourhood, bounds)
+            .into_iter()
+            .chain([bounds]);
+        for area in areas {
+            if let Some(rect) = by_distance(grid(size, area))
+                .into_iter()
+                .find(|rect| is_free(*rect, taken) && leader_is_free(*rect, window, taken))
+            {
+                return Placement { rect, leader: true };
+            }
+        }
+    }
+
+    Placement {
+        rect: fit(preferred),
+        leader: false,
+    }
+}
+
+/// The pieces of a line from the center of `label` to the closest point of `window`, first going
+/// horizontally and then vertically.
+pub fn leader_segments(label: Rect, window: Rect) -> Vec<Rect> {
+    let start = (label.0 + label.2 / 2, label.1 + label.3 / 2);
+    let end = (
+        start.0.clamp(window.0, window.0 + (window.2 - 1).max(0)),
+        start.1.clamp(window.1, window.1 + (window.3 - 1).max(0)),
+    );
+    let half = LEADER_WIDTH / 2;
+    let mut segments = vec![];
+    if start.0 != end.0 {
+        segments.push((
+            start.0.min(end.0) - half,
+            start.1 - half,
+            (end.0 - start.0).abs() + LEADER_WIDTH,
+            LEADER_WIDTH,
+        ));
+    }
+    if start.1 != end.1 {
+        segments.push((
+            end.0 - half,
+            start.1.min(end.1) - half,
+            LEADER_WIDTH,
+            (end.1 - start.1).abs() + LEADER_WIDTH,
+        ));
+    }
+    segments
+}
+
+/// The smallest rect containing all of `rects`.
+pub fn bounding_rect(rects: &[Rect]) -> Option<Rect> {
+    rects.iter().copied().reduce(|a, b| {
+        let (x, y) = (a.0.min(b.0), a.1.min(b.1));
+        (
+            x,
+            y,
+            (a.0 + a.2).max(b.0 + b.2) - x,
+            (a.1 + a.3).max(b.1 + b.3) - y,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: Rect = (0, 0, 1920, 1080);
+
+    #[test]
+    fn test_place_preferred() {
+        let placement = place((0, 0, 100, 50), (0, 0, 960, 1080), Some(OUTPUT), &[]);
+        assert_eq!(
+            placement,
+            Placement {
+                rect: (0, 0, 100, 50),
+                leader: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_place_other_anchor() {
+        // Two windows stacked at the same spot (eg tabs) can't both have their label top left.
+        let window = (0, 0, 960, 1080);
+        let taken = [(0, 0, 100, 50)];
+        let placement = place((0, 0, 100, 50), window, Some(OUTPUT), &taken);
+        assert!(!placement.leader);
+        assert_eq!(placement.rect, (430, 0, 100, 50));
+    }
+
+    #[test]
+    fn test_place_stays_on_output() {
+        // The window reaches beyond the right edge of the output.
+        let placement = place((1900, 0, 100, 50), (1900, 0, 300, 300), Some(OUTPUT), &[]);
+        assert_eq!(placement.rect, (1820, 0, 100, 50));
+    }
+
+    #[test]
+    fn test_place_leader() {
+        // The window is completely covered by labels of other windows.
+        let window = (100, 100, 100, 50);
+        let taken = [(100, 100, 100, 50)];
+        let placement = place((100, 100, 100, 50), window, Some(OUTPUT), &taken);
+        assert!(placement.leader);
+        assert!(is_free(placement.rect, &taken));
+        assert!(distance(placement.rect, window) <= 50 * 50);
+
+        // Without a known output, the label has to overlap.
+        let placement = place((100, 100, 100, 50), window, None, &taken);
+        assert_eq!(
+            placement,
+            Placement {
+                rect: (100, 100, 100, 50),
+                leader: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_place_leader_around_labels() {
+        // The window is covered and there are labels above and left of it, so leaders from the
+        // closest spots up and to the left would cross those.
+        let window = (500, 500, 100, 50);
+        let taken = [
+            (500, 500, 100, 50),
+            (500, 440, 100, 50),
+            (390, 500, 100, 50),
+        ];
+        let placement = place((500, 500, 100, 50), window, Some(OUTPUT), &taken);
+        assert!(placement.leader);
+        assert!(is_free(placement.rect, &taken));
+        for segment in leader_segments(placement.rect, window) {
+            assert!(is_free(segment, &taken[1..]));
+        }
+    }
+
+    #[test]
+    fn test_grid_is_capped() {
+        // Tiny labels on a 4K output.
+        let positions = grid((20, 10), (0, 0, 3840, 2160));
+        assert!(positions.len() <= MAX_GRID_POSITIONS);
+        assert!(positions.contains(&(0, 0, 20, 10)));
+        assert!(grid((100, 50), (0, 0, 300, 100)).contains(&(50, 25, 100, 50)));
+    }
+
+    #[test]
+    fn test_leader_segments() {
+        // Straight to the left of the window.
+        assert_eq!(
+            leader_segments((0, 100, 100, 50), (300, 0, 100, 300)),
+            vec![(49, 124, 252, 2)]
+        );
+        // Above and to the left, so it needs an elbow.
+        assert_eq!(
+            leader_segments((0, 0, 100, 50), (300, 200, 100, 100)),
+            vec![(49, 24, 252, 2), (299, 24, 2, 177)]
+        );
+        // Inside of the window.
+        assert!(leader_segments((310, 210, 20, 20), (300, 200, 100, 100)).is_empty());
+
+        assert_eq!(
+            bounding_rect(&[(49, 24, 252, 2), (299, 24, 2, 177)]),
+            Some((49, 24, 252, 177))
+        );
+        assert_eq!(bounding_rect(&[]), None);
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Themes that ship with wmfocus.
+pub const BUILTIN_THEMES: &[(&str, &str)] = &[
+    ("dark", include_str!("../themes/dark.toml")),
+    ("light", include_str!("../themes/light.toml")),
+    (
+        "high-contrast",
+        include_str!("../themes/high-contrast.toml"),
+    ),
+    ("solarized", include_str!("../themes/solarized.toml")),
+];
+
+/// A named set of settings, keyed by the long name of the flag they set (eg `bgcolor`).
+#[derive(Debug)]
+pub struct Theme {
+    pub values: BTreeMap<String, String>,
+}
+
+/// Directory containing user themes, usually `~/.config/wmfocus/themes`.
+fn themes_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|config| config.join("wmfocus").join("themes"))
+}
+
+/// Parse a theme from TOML. Values are kept as strings so they can go through the same parsers
+/// as the command line flags.
+pub fn parse(source: &str) -> Result<Theme> {
+    let table: toml::value::Table = toml::from_str(source).context("Couldn't parse theme")?;
+    let mut values = BTreeMap::new();
+    for (key, value) in table {
+        let value = match value {
+            toml::Value::String(s) => s,
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Float(f) => f.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            _ => bail!("Unsupported value for theme setting '{}'", key),
+        };
+        values.insert(key, value);
+    }
+    Ok(Theme { values })
+}
+
+/// Collect the `wmfocus.*` (or `wmfocus*`) entries of an X resource database, as returned by
+/// `xrdb -query`, into a theme.
+pub fn from_xresources(resources: &str) -> Theme {
+    let values = resources
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter_map(|(key, value)| {
+            let key = key.trim();
+            let key = key
+                .strip_prefix("wmfocus.")
+                .or_else(|| key.strip_prefix("wmfocus*"))?;
+            Some((key.to_string(), value.trim().to_string()))
+        })
+        .collect();
+    Theme { values }
+}
+
+/// Load the theme `name`.
+///
+/// `name` is either a path to a theme file, the name of a theme in the user's themes directory or
+/// the name of a built-in theme. User themes take precedence over built-in ones.
+pub fn load(name: &str) -> Result<Theme> {
+    let path = if name.contains('/') {
+        Some(PathBuf::from(name))
+    } else {
+        themes_dir()
+            .map(|dir| dir.join(format!("{}.toml", name)))
+            .filter(|path| path.exists())
+    };
+    if let Some(path) = path {
+        info!("Loading theme from {}", path.display());
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Couldn't read theme file {}", path.display()))?;
+        return parse(&source);
+    }
+
+    let (_, source) = BUILTIN_THEMES
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .with_context(|| {
+            let builtins: Vec<_> = BUILTIN_THEMES.iter().map(|(builtin, _)| *builtin).collect();
+            format!(
+                "No such theme, built-in themes are: {}",
+                builtins.join(", ")
+            )
+        })?;
+    info!("Using built-in theme '{}'", name);
+    parse(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let theme =
+            parse("bgcolor = \"#000000\"\nborderwidth = 2\nradius = 4.5\nicons = true").unwrap();
+        assert_eq!(theme.values["bgcolor"], "#000000");
+        assert_eq!(theme.values["borderwidth"], "2");
+        assert_eq!(theme.values["radius"], "4.5");
+        assert_eq!(theme.values["icons"], "true");
+
+        assert!(parse("bgcolor = [1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_from_xresources() {
+        let theme = from_xresources(
+            "*background:\t#1d1f21\nwmfocus.bgcolor:\t#1d1f21\nwmfocus*textcolor: rgba(1, 2, 3, 0.5)\nXTerm*font: fixed\n",
+        );
+        assert_eq!(theme.values.len(), 2);
+        assert_eq!(theme.values["bgcolor"], "#1d1f21");
+        assert_eq!(theme.values["textcolor"], "rgba(1, 2, 3, 0.5)");
+    }
+
+    #[test]
+    fn test_builtin_themes() {
+        for (name, source) in BUILTIN_THEMES {
+            assert!(parse(source).is_ok(), "built-in theme '{}' is broken", name);
+        }
+    }
+}
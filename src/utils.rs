@@ -1,15 +1,24 @@
 use anyhow::{bail, Context, Result};
 use itertools::Itertools;
-use log::debug;
-use regex::Regex;
-use std::ffi::CStr;
+use log::{debug, warn};
+use pango::prelude::*;
+use std::collections::HashMap;
+use std::env;
+use std::f64::consts::{FRAC_PI_2, PI};
+use std::ffi::CString;
+use std::fs::File;
 use std::iter;
-use std::thread::sleep;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::{self, sleep};
 use std::time::{Duration, Instant};
 use xcb::ffi::xcb_visualid_t;
+use xkbcommon::xkb;
 
-use crate::args::AppConfig;
-use crate::{DesktopWindow, RenderWindow};
+use crate::args::{
+    Action, AppConfig, FontConfig, HorizontalAlign, LabelFonts, LabelPart, LabelTemplate,
+};
+use crate::{DesktopWindow, QueryWindow, RenderWindow};
 
 /// Given a list of `current_hints` and a bunch of `hint_chars`, this finds a unique combination
 /// of characters that doesn't yet exist in `current_hints`. `max_count` is the maximum possible
@@ -19,11 +28,7 @@ pub fn get_next_hint(
     hint_chars: &str,
     max_count: usize,
 ) -> Result<String> {
-    // Figure out which size we need.
-    let mut size_required = 1;
-    while hint_chars.len().pow(size_required) < max_count {
-        size_required += 1;
-    }
+    let size_required = hint_length(hint_chars, max_count);
     let mut ret = hint_chars
         .chars()
         .next()
@@ -42,6 +47,48 @@ pub fn get_next_hint(
     Ok(ret)
 }
 
+/// Number of characters hints need so that there are at least `max_count` different ones.
+fn hint_length(hint_chars: &str, max_count: usize) -> u32 {
+    let mut size_required = 1;
+    while hint_chars.chars().count().pow(size_required) < max_count {
+        size_required += 1;
+    }
+    size_required
+}
+
+/// Find hints for the windows with `window_ids`. Windows keep their hint from `previous_hints` if
+/// it still fits, ie it's made up of `hint_chars`, is as long as hints need to be now and isn't
+/// taken by another window. That way hints stay the same from one run to the next. All other
+/// windows get new hints.
+pub fn assign_hints(
+    window_ids: &[i64],
+    hint_chars: &str,
+    previous_hints: &HashMap<i64, String>,
+) -> Result<Vec<String>> {
+    let length = hint_length(hint_chars, window_ids.len()) as usize;
+    let mut kept: Vec<Option<String>> = vec![];
+    for id in window_ids {
+        let hint = previous_hints.get(id).filter(|hint| {
+            hint.chars().count() == length
+                && hint.chars().all(|c| hint_chars.contains(c))
+                && !kept.contains(&Some(hint.to_string()))
+        });
+        kept.push(hint.cloned());
+    }
+
+    let mut hints: Vec<String> = kept.iter().flatten().cloned().collect();
+    kept.into_iter()
+        .map(|hint| match hint {
+            Some(hint) => Ok(hint),
+            None => {
+                let hint = get_next_hint(hints.iter().collect(), hint_chars, window_ids.len())?;
+                hints.push(hint.clone());
+                Ok(hint)
+            }
+        })
+        .collect()
+}
+
 pub fn find_visual(conn: &xcb::Connection, visual: xcb_visualid_t) -> Option<xcb::Visualtype> {
     for screen in conn.get_setup().roots() {
         for depth in screen.allowed_depths() {
@@ -55,16 +102,789 @@ pub fn find_visual(conn: &xcb::Connection, visual: xcb_visualid_t) -> Option<xcb
     None
 }
 
-pub fn extents_for_text(text: &str, family: &str, size: f64) -> Result<cairo::TextExtents> {
-    // Create a buffer image that should be large enough.
-    // TODO: Figure out the maximum size from the largest window on the desktop.
-    // For now we'll use made-up maximum values.
-    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 1024, 1024)
-        .context("Couldn't create ImageSurface")?;
-    let cr = cairo::Context::new(&surface).context("Couldn't create Cairo Surface")?;
-    cr.select_font_face(family, cairo::FontSlant::Normal, cairo::FontWeight::Normal);
-    cr.set_font_size(size);
-    cr.text_extents(text).context("Couldn't create TextExtents")
+/// Find a 32-bit TrueColor visual on `screen` which can be used for windows with an alpha channel.
+fn find_argb_visual(screen: &xcb::Screen) -> Option<xcb::Visualtype> {
+    screen
+        .allowed_depths()
+        .filter(|depth| depth.depth() == 32)
+        .flat_map(|depth| depth.visuals())
+        .find(|vis| u32::from(vis.class()) == xcb::VISUAL_CLASS_TRUE_COLOR)
+}
+
+/// Create a colormap for windows with an alpha channel on `screen`. It can be shared by any number
+/// of windows and has to be freed once none of them are left.
+pub fn create_argb_colormap(conn: &xcb::Connection, screen: &xcb::Screen) -> Result<u32> {
+    let visual =
+        find_argb_visual(screen).context("Couldn't find a visual with an alpha channel")?;
+    let colormap = conn.generate_id();
+    xcb::create_colormap(
+        conn,
+        xcb::COLORMAP_ALLOC_NONE as u8,
+        colormap,
+        screen.root(),
+        visual.visual_id(),
+    );
+    Ok(colormap)
+}
+
+/// Create an unmapped override-redirect window at `rect` in the format (x, y, w, h) and return its
+/// id together with a Cairo context to draw onto it. `opacity` is set as the window opacity hint
+/// for compositors. With an `argb_colormap` from `create_argb_colormap`, the window gets an alpha
+/// channel of its own instead.
+pub fn create_overlay_window(
+    conn: &xcb::Connection,
+    screen: &xcb::Screen,
+    rect: (i16, i16, u16, u16),
+    opacity: f64,
+    argb_colormap: Option<u32>,
+) -> Result<(u32, cairo::Context)> {
+    let mut values = vec![
+        (xcb::CW_BACK_PIXEL, screen.black_pixel()),
+        (
+            xcb::CW_EVENT_MASK,
+            xcb::EVENT_MASK_EXPOSURE
+                | xcb::EVENT_MASK_KEY_PRESS
+                | xcb::EVENT_MASK_BUTTON_PRESS
+                | xcb::EVENT_MASK_BUTTON_RELEASE,
+        ),
+        (xcb::CW_OVERRIDE_REDIRECT, 1),
+    ];
+
+    // Windows with an alpha channel can't inherit anything from the root window, so they need
+    // their own visual, colormap and border.
+    let (depth, mut visual) = if let Some(colormap) = argb_colormap {
+        let visual =
+            find_argb_visual(screen).context("Couldn't find a visual with an alpha channel")?;
+        values[0] = (xcb::CW_BACK_PIXEL, 0);
+        values.push((xcb::CW_BORDER_PIXEL, 0));
+        values.push((xcb::CW_COLORMAP, colormap));
+        (32, visual)
+    } else {
+        let visual = find_visual(conn, screen.root_visual()).context("Couldn't find visual")?;
+        (xcb::COPY_FROM_PARENT as u8, visual)
+    };
+
+    let xcb_window_id = conn.generate_id();
+
+    // Create the actual window.
+    xcb::create_window(
+        conn,
+        depth,
+        xcb_window_id,
+        screen.root(),
+        rect.0,
+        rect.1,
+        rect.2,
+        rect.3,
+        0,
+        xcb::WINDOW_CLASS_INPUT_OUTPUT as u16,
+        visual.visual_id(),
+        &values,
+    );
+
+    set_opacity(conn, xcb_window_id, opacity)?;
+
+    conn.flush();
+
+    let cairo_xcb_conn = unsafe {
+        cairo::XCBConnection::from_raw_none(conn.get_raw_conn() as *mut cairo_sys::xcb_connection_t)
+    };
+    let cairo_xcb_drawable = cairo::XCBDrawable(xcb_window_id);
+    let raw_visualtype = &mut visual.base as *mut xcb::ffi::xcb_visualtype_t;
+    let cairo_xcb_visual = unsafe {
+        cairo::XCBVisualType::from_raw_none(raw_visualtype as *mut cairo_sys::xcb_visualtype_t)
+    };
+    let surface = cairo::XCBSurface::create(
+        &cairo_xcb_conn,
+        &cairo_xcb_drawable,
+        &cairo_xcb_visual,
+        rect.2.into(),
+        rect.3.into(),
+    )
+    .context("Couldn't create Cairo Surface")?;
+    let cairo_context = cairo::Context::new(&surface).context("Couldn't create Cairo Context")?;
+
+    Ok((xcb_window_id, cairo_context))
+}
+
+/// Set the opacity hint of `xcb_window_id` for compositors.
+pub fn set_opacity(conn: &xcb::Connection, xcb_window_id: u32, opacity: f64) -> Result<()> {
+    let opacity_atom = xcb::intern_atom(conn, false, "_NET_WM_WINDOW_OPACITY")
+        .get_reply()
+        .context("Couldn't create atom _NET_WM_WINDOW_OPACITY")?
+        .atom();
+    let opacity = (0xFFFFFFFFu64 as f64 * opacity) as u32;
+    xcb::change_property(
+        conn,
+        xcb::PROP_MODE_REPLACE as u8,
+        xcb_window_id,
+        opacity_atom,
+        xcb::ATOM_CARDINAL,
+        32,
+        &[opacity],
+    );
+    Ok(())
+}
+
+/// Cut `hole`, relative to the window, out of `xcb_window_id` so that whatever is below it shows through.
+pub fn cut_out_rect(conn: &xcb::Connection, xcb_window_id: u32, hole: (i16, i16, u16, u16)) {
+    xcb::shape::rectangles(
+        conn,
+        xcb::shape::SO_SUBTRACT as u8,
+        xcb::shape::SK_BOUNDING as u8,
+        xcb::CLIP_ORDERING_UNSORTED as u8,
+        xcb_window_id,
+        0,
+        0,
+        &[xcb::Rectangle::new(hole.0, hole.1, hole.2, hole.3)],
+    );
+}
+
+/// Restrict `xcb_window_id` to `rects`, relative to the window, so that only they are visible.
+pub fn shape_window(conn: &xcb::Connection, xcb_window_id: u32, rects: &[(i16, i16, u16, u16)]) {
+    let rectangles: Vec<_> = rects
+        .iter()
+        .map(|rect| xcb::Rectangle::new(rect.0, rect.1, rect.2, rect.3))
+        .collect();
+    xcb::shape::rectangles(
+        conn,
+        xcb::shape::SO_SET as u8,
+        xcb::shape::SK_BOUNDING as u8,
+        xcb::CLIP_ORDERING_UNSORTED as u8,
+        xcb_window_id,
+        0,
+        0,
+        &rectangles,
+    );
+}
+
+/// Fill a whole overlay window such as the dim layer or a frame with `color`. Its alpha is used as
+/// the window opacity instead.
+pub fn draw_fill(cairo_context: &cairo::Context, color: (f64, f64, f64, f64)) -> Result<()> {
+    cairo_context.set_source_rgb(color.0, color.1, color.2);
+    cairo_context.paint().context("Couldn't fill window")?;
+    Ok(())
+}
+
+/// Pango attributes to draw the text between the byte indices `start` and `end` in `color`.
+fn color_attributes(
+    color: (f64, f64, f64, f64),
+    start: usize,
+    end: usize,
+) -> [pango::Attribute; 2] {
+    let to_u16 = |c: f64| (c * f64::from(u16::MAX)).round() as u16;
+    let mut attrs = [
+        pango::Attribute::new_foreground(to_u16(color.0), to_u16(color.1), to_u16(color.2)),
+        pango::Attribute::new_foreground_alpha(to_u16(color.3)),
+    ];
+    for attr in &mut attrs {
+        attr.set_start_index(start as u32);
+        attr.set_end_index(end as u32);
+    }
+    attrs
+}
+
+thread_local! {
+    /// The Pango context all text is laid out with, for measuring as well as for drawing. That way
+    /// fonts are only loaded once and text gets drawn exactly as large as it was measured.
+    static PANGO_CONTEXT: Option<pango::Context> = create_pango_context();
+}
+
+/// Create a Pango context with fixed font options, independent of the surface text is drawn on.
+fn create_pango_context() -> Option<pango::Context> {
+    let context = pangocairo::FontMap::default()?.create_context()?;
+    let font_options = cairo::FontOptions::new().ok()?;
+    pangocairo::context_set_font_options(&context, Some(&font_options));
+    Some(context)
+}
+
+/// Create a Pango layout of `text` in `font`.
+fn create_layout(font: &FontConfig, text: &str) -> Result<pango::Layout> {
+    PANGO_CONTEXT.with(|context| {
+        let context = context.as_ref().context("Couldn't create Pango context")?;
+        let layout = pango::Layout::new(context);
+        layout.set_font_description(Some(&font.description));
+        layout.set_text(text);
+        Ok(layout)
+    })
+}
+
+/// Measure `layout`.
+///
+/// Unlike with Cairo's own text extents, the bearings are relative to the top-left corner of the
+/// layout instead of the baseline because that is where Pango starts drawing.
+fn layout_extents(layout: &pango::Layout) -> cairo::TextExtents {
+    let (ink, logical) = layout.pixel_extents();
+    cairo::TextExtents {
+        x_bearing: ink.x.into(),
+        y_bearing: ink.y.into(),
+        width: ink.width.into(),
+        height: ink.height.into(),
+        x_advance: logical.width.into(),
+        y_advance: 0.0,
+    }
+}
+
+pub fn extents_for_text(text: &str, font: &FontConfig) -> Result<cairo::TextExtents> {
+    Ok(layout_extents(&create_layout(font, text)?))
+}
+
+/// Pick the best icon for `size` out of `_NET_WM_ICON` property `data`.
+///
+/// The property is a list of icons, each consisting of width, height and that many ARGB pixels.
+/// Returns the smallest icon that is at least `size` large or otherwise the largest one.
+pub fn pick_icon(data: &[u32], size: u32) -> Option<(u32, u32, &[u32])> {
+    let mut icons = vec![];
+    let mut rest = data;
+    while rest.len() > 2 {
+        let (width, height) = (rest[0], rest[1]);
+        let len = width as usize * height as usize;
+        if len == 0 || rest.len() < 2 + len {
+            break;
+        }
+        icons.push((width, height, &rest[2..2 + len]));
+        rest = &rest[2 + len..];
+    }
+    icons
+        .iter()
+        .filter(|icon| icon.0.min(icon.1) >= size)
+        .min_by_key(|icon| icon.0 * icon.1)
+        .or_else(|| icons.iter().max_by_key(|icon| icon.0 * icon.1))
+        .copied()
+}
+
+/// Get the X resource database (what `xrdb -query` prints) from the root window.
+pub fn get_xresources(conn: &xcb::Connection, screen: &xcb::Screen) -> Option<String> {
+    let reply = xcb::get_property(
+        conn,
+        false,
+        screen.root(),
+        xcb::ATOM_RESOURCE_MANAGER,
+        xcb::ATOM_STRING,
+        0,
+        u32::MAX,
+    )
+    .get_reply()
+    .ok()?;
+    if reply.value_len() == 0 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(reply.value::<u8>()).into_owned())
+}
+
+/// Handle the events of the X connection set up by `listen` in the background with `on_event`.
+/// Returns once the connection is set up.
+fn watch_events<L, F>(listen: L, mut on_event: F) -> Result<()>
+where
+    L: FnOnce() -> Result<xcb::Connection> + Send + 'static,
+    F: FnMut(&xcb::Connection, xcb::GenericEvent) + Send + 'static,
+{
+    // The connection can't be moved to another thread so it's set up in there.
+    let (setup_tx, setup_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let conn = match listen() {
+            Ok(conn) => {
+                setup_tx.send(Ok(())).unwrap();
+                conn
+            }
+            Err(e) => {
+                setup_tx.send(Err(e)).unwrap();
+                return;
+            }
+        };
+        while let Some(event) = conn.wait_for_event() {
+            on_event(&conn, event);
+        }
+        warn!("Lost Xorg connection, not watching for changes anymore");
+    });
+    setup_rx.recv()?
+}
+
+/// Call `on_change` in the background whenever top-level windows are moved, resized or shown,
+/// which also happens when the window manager changes its layout. Changes coming in quick
+/// succession, eg while dragging a window, only cause one call. Our own overlays are ignored.
+pub fn watch_window_geometry<F>(mut on_change: F) -> Result<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    watch_events(listen_for_geometry_changes, move |conn, event| {
+        let changed = match event.response_type() & !0x80 {
+            xcb::CONFIGURE_NOTIFY => {
+                let event: &xcb::ConfigureNotifyEvent = unsafe { xcb::cast_event(&event) };
+                !event.override_redirect()
+            }
+            xcb::MAP_NOTIFY => {
+                let event: &xcb::MapNotifyEvent = unsafe { xcb::cast_event(&event) };
+                !event.override_redirect()
+            }
+            _ => false,
+        };
+        if changed {
+            sleep(Duration::from_millis(50));
+            while conn.poll_for_event().is_some() {}
+            on_change();
+        }
+    })
+}
+
+/// Connect to X and ask for events about the top-level windows of every screen.
+fn listen_for_geometry_changes() -> Result<xcb::Connection> {
+    let (conn, _) = xcb::Connection::connect(None).context("No Xorg connection")?;
+    for screen in conn.get_setup().roots() {
+        xcb::change_window_attributes_checked(
+            &conn,
+            screen.root(),
+            &[(xcb::CW_EVENT_MASK, xcb::EVENT_MASK_SUBSTRUCTURE_NOTIFY)],
+        )
+        .request_check()
+        .context("Couldn't listen for window changes")?;
+    }
+    Ok(conn)
+}
+
+/// Part of the X server setup that hints depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SetupChange {
+    Keyboard,
+    Resources,
+    Outputs,
+}
+
+/// Call `on_change` in the background whenever the keyboard mapping, the X resources or the
+/// outputs change.
+pub fn watch_setup<F>(mut on_change: F) -> Result<()>
+where
+    F: FnMut(SetupChange) + Send + 'static,
+{
+    watch_events(listen_for_setup_changes, move |conn, event| {
+        let randr_base = conn
+            .get_extension_data(xcb::randr::id())
+            .map(|data| data.first_event());
+        let response_type = event.response_type() & !0x80;
+        let change = match response_type {
+            xcb::MAPPING_NOTIFY => {
+                let event: &xcb::MappingNotifyEvent = unsafe { xcb::cast_event(&event) };
+                (event.request() != xcb::MAPPING_POINTER as u8).then_some(SetupChange::Keyboard)
+            }
+            xcb::PROPERTY_NOTIFY => {
+                let event: &xcb::PropertyNotifyEvent = unsafe { xcb::cast_event(&event) };
+                (event.atom() == xcb::ATOM_RESOURCE_MANAGER).then_some(SetupChange::Resources)
+            }
+            _ if randr_base.is_some_and(|base| {
+                response_type == base + xcb::randr::SCREEN_CHANGE_NOTIFY
+                    || response_type == base + xcb::randr::NOTIFY
+            }) =>
+            {
+                Some(SetupChange::Outputs)
+            }
+            _ => None,
+        };
+        if let Some(change) = change {
+            debug!("X server reported a change to {:?}", change);
+            on_change(change);
+        }
+    })
+}
+
+/// Connect to X and ask for events about changes to the resources and outputs of every screen.
+/// Changes to the keyboard mapping are reported to every client anyway.
+fn listen_for_setup_changes() -> Result<xcb::Connection> {
+    let (conn, _) = xcb::Connection::connect(None).context("No Xorg connection")?;
+    for screen in conn.get_setup().roots() {
+        xcb::change_window_attributes_checked(
+            &conn,
+            screen.root(),
+            &[(xcb::CW_EVENT_MASK, xcb::EVENT_MASK_PROPERTY_CHANGE)],
+        )
+        .request_check()
+        .context("Couldn't listen for resource changes")?;
+        let outputs_changed = xcb::randr::NOTIFY_MASK_SCREEN_CHANGE
+            | xcb::randr::NOTIFY_MASK_CRTC_CHANGE
+            | xcb::randr::NOTIFY_MASK_OUTPUT_CHANGE;
+        if let Err(e) =
+            xcb::randr::select_input_checked(&conn, screen.root(), outputs_changed as u16)
+                .request_check()
+        {
+            warn!("Couldn't listen for output changes: {:?}", e);
+        }
+    }
+    Ok(conn)
+}
+
+/// Read the `_NET_WM_ICON` of `x_window_id` into a surface, using the icon closest to `size`.
+fn get_net_wm_icon(
+    conn: &xcb::Connection,
+    x_window_id: u32,
+    size: u32,
+) -> Result<Option<cairo::ImageSurface>> {
+    let icon_atom = xcb::intern_atom(conn, false, "_NET_WM_ICON")
+        .get_reply()
+        .context("Couldn't create atom _NET_WM_ICON")?
+        .atom();
+    let reply = xcb::get_property(
+        conn,
+        false,
+        x_window_id,
+        icon_atom,
+        xcb::ATOM_CARDINAL,
+        0,
+        u32::MAX,
+    )
+    .get_reply()
+    .context("Couldn't get _NET_WM_ICON")?;
+    let (width, height, pixels) = match pick_icon(reply.value::<u32>(), size) {
+        Some(icon) => icon,
+        None => return Ok(None),
+    };
+
+    // Cairo expects premultiplied alpha.
+    let mut data = Vec::with_capacity(pixels.len() * 4);
+    for argb in pixels {
+        let alpha = argb >> 24;
+        let premultiply = |c: u32| (c * alpha + 127) / 255;
+        let pixel = alpha << 24
+            | premultiply(argb >> 16 & 0xff) << 16
+            | premultiply(argb >> 8 & 0xff) << 8
+            | premultiply(argb & 0xff);
+        data.extend_from_slice(&pixel.to_ne_bytes());
+    }
+    let surface = cairo::ImageSurface::create_for_data(
+        data,
+        cairo::Format::ARgb32,
+        width as i32,
+        height as i32,
+        width as i32 * 4,
+    )
+    .context("Couldn't create icon surface")?;
+    Ok(Some(surface))
+}
+
+/// Look up an icon named after the window `class` in the hicolor icon theme or the legacy pixmaps
+/// directory.
+fn find_theme_icon(class: &str, size: u32) -> Option<cairo::ImageSurface> {
+    if class.is_empty() {
+        return None;
+    }
+    let data_home = env::var("XDG_DATA_HOME").ok().or_else(|| {
+        env::var("HOME")
+            .ok()
+            .map(|home| format!("{}/.local/share", home))
+    });
+    let data_dirs =
+        env::var("XDG_DATA_DIRS").unwrap_or_else(|_| "/usr/local/share:/usr/share".to_string());
+    let dirs: Vec<PathBuf> = data_home
+        .iter()
+        .map(String::as_str)
+        .chain(data_dirs.split(':'))
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .collect();
+
+    // Prefer the smallest icons which are still large enough, then the larger ones.
+    let mut sizes = vec![16, 22, 24, 32, 48, 64, 96, 128, 256, 512];
+    sizes.sort_by_key(|s| (*s < size, if *s < size { size - s } else { s - size }));
+
+    let names = [class.to_string(), class.to_lowercase()];
+    let mut candidates = vec![];
+    for s in sizes {
+        for dir in &dirs {
+            for name in &names {
+                candidates.push(dir.join(format!("icons/hicolor/{0}x{0}/apps/{1}.png", s, name)));
+            }
+        }
+    }
+    for dir in &dirs {
+        for name in &names {
+            candidates.push(dir.join(format!("pixmaps/{}.png", name)));
+        }
+    }
+
+    candidates.iter().find_map(|path| {
+        let mut file = File::open(path).ok()?;
+        debug!("Loading icon from {:?}", path);
+        cairo::ImageSurface::create_from_png(&mut file).ok()
+    })
+}
+
+/// Get an icon of roughly `size` for `dw`, either from the window itself or the icon theme.
+pub fn get_window_icon(
+    conn: &xcb::Connection,
+    dw: &DesktopWindow,
+    size: u32,
+) -> Option<cairo::ImageSurface> {
+    if let Some(x_window_id) = dw.x_window_id {
+        match get_net_wm_icon(conn, x_window_id as u32, size) {
+            Ok(Some(icon)) => return Some(icon),
+            Ok(None) => debug!("No _NET_WM_ICON for {:?}", dw),
+            Err(e) => warn!("Couldn't read icon of {:?}: {:?}", dw, e),
+        }
+    }
+    find_theme_icon(&dw.class, size)
+}
+
+/// Part of a label as it is drawn, either the hint itself or window metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelSegment {
+    Hint,
+    Meta(String),
+}
+
+/// Fill in the label `template` for `dw`, merging adjacent metadata into a single segment.
+pub fn expand_label(template: &[LabelPart], dw: &DesktopWindow) -> Vec<LabelSegment> {
+    let mut segments = vec![];
+    for part in template {
+        let text = match part {
+            LabelPart::Hint => {
+                segments.push(LabelSegment::Hint);
+                continue;
+            }
+            LabelPart::Text(text) => text,
+            LabelPart::Title => &dw.title,
+            LabelPart::Class => &dw.class,
+        };
+        if let Some(LabelSegment::Meta(meta)) = segments.last_mut() {
+            meta.push_str(text);
+        } else {
+            segments.push(LabelSegment::Meta(text.to_string()));
+        }
+    }
+    segments
+}
+
+/// Shorten `text` and append an ellipsis until it fits into `max_width` according to `measure`.
+pub fn ellipsize<F>(text: &str, max_width: f64, measure: F) -> String
+where
+    F: Fn(&str) -> f64,
+{
+    if measure(text) <= max_width {
+        return text.to_string();
+    }
+    let mut chars: Vec<char> = text.chars().collect();
+    while chars.pop().is_some() {
+        let shortened = format!("{}…", chars.iter().collect::<String>().trim_end());
+        if measure(&shortened) <= max_width {
+            return shortened;
+        }
+    }
+    String::new()
+}
+
+/// Create a layout for a label consisting of `segments` in `fonts`. Window metadata is set
+/// in the metadata font and `current_hints` typed so far are highlighted in the hint.
+fn create_label_layout(
+    app_config: &AppConfig,
+    fonts: &LabelFonts,
+    segments: &[LabelSegment],
+    hint: &str,
+    current_hints: &str,
+    is_focused: bool,
+) -> Result<pango::Layout> {
+    let (text_color, text_color_alt) = if is_focused {
+        (
+            app_config.text_color_current,
+            app_config.text_color_current_alt,
+        )
+    } else {
+        (app_config.text_color, app_config.text_color_alt)
+    };
+
+    let mut text = String::new();
+    let attrs = pango::AttrList::new();
+    for segment in segments {
+        let start = text.len();
+        match segment {
+            LabelSegment::Hint => {
+                text.push_str(hint);
+                let typed = if hint.starts_with(current_hints) {
+                    current_hints.len()
+                } else {
+                    0
+                };
+                for attr in color_attributes(text_color_alt, start, start + typed) {
+                    attrs.insert(attr);
+                }
+                for attr in color_attributes(text_color, start + typed, text.len()) {
+                    attrs.insert(attr);
+                }
+            }
+            LabelSegment::Meta(meta) => {
+                text.push_str(meta);
+                let mut font_attr = pango::Attribute::new_font_desc(&fonts.meta.description);
+                font_attr.set_start_index(start as u32);
+                font_attr.set_end_index(text.len() as u32);
+                attrs.insert(font_attr);
+                for attr in color_attributes(text_color, start, text.len()) {
+                    attrs.insert(attr);
+                }
+            }
+        }
+    }
+
+    let layout = create_layout(&fonts.hint, &text)?;
+    layout.set_attributes(Some(&attrs));
+    Ok(layout)
+}
+
+/// Expand the label template for `dw` and measure it in `fonts`. Window metadata is ellipsized so
+/// that the whole label fits into `max_width`.
+pub fn layout_label(
+    app_config: &AppConfig,
+    fonts: &LabelFonts,
+    template: &LabelTemplate,
+    hint: &str,
+    dw: &DesktopWindow,
+    max_width: f64,
+) -> Result<(Vec<LabelSegment>, cairo::TextExtents)> {
+    let measure = |segments: &[LabelSegment]| {
+        create_label_layout(app_config, fonts, segments, hint, "", false)
+            .map(|layout| layout_extents(&layout))
+    };
+
+    // Shorten metadata starting from the end of the label until everything fits.
+    let mut segments = expand_label(&template.parts, dw);
+    let mut advances = segments
+        .iter()
+        .map(|segment| measure(std::slice::from_ref(segment)).map(|e| e.x_advance))
+        .collect::<Result<Vec<_>>>()?;
+    for i in (0..segments.len()).rev() {
+        let total: f64 = advances.iter().sum();
+        if total <= max_width {
+            break;
+        }
+        if let LabelSegment::Meta(text) = &segments[i] {
+            let shortened = ellipsize(text, max_width - (total - advances[i]), |t| {
+                measure(&[LabelSegment::Meta(t.to_string())]).map_or(f64::MAX, |e| e.x_advance)
+            });
+            segments[i] = LabelSegment::Meta(shortened);
+            advances[i] = measure(&segments[i..=i])?.x_advance;
+        }
+    }
+
+    let label_extents = measure(&segments)?;
+    Ok((segments, label_extents))
+}
+
+/// Horizontal advance of the hint `text` in `font`.
+pub fn hint_advance(font: &FontConfig, text: &str) -> Result<f64> {
+    let layout = create_layout(font, text)?;
+    Ok(layout_extents(&layout).x_advance)
+}
+
+/// How far a box aligned with `align` moves to the right when it gets `width_change` narrower.
+pub fn align_shift(align: HorizontalAlign, width_change: i32) -> i32 {
+    match align {
+        HorizontalAlign::Left => 0,
+        HorizontalAlign::Center => width_change / 2,
+        HorizontalAlign::Right => width_change,
+    }
+}
+
+/// Rect of the label for `hint` with its full size `full_rect` after `pressed_keys` were typed.
+/// With `shrink`, labels that still match leave out the characters typed so far as measured by
+/// `advance`. All other labels get their full size back, as they show the whole hint.
+pub fn label_rect<F>(
+    full_rect: (i32, i32, i32, i32),
+    align: HorizontalAlign,
+    shrink: bool,
+    hint: &str,
+    pressed_keys: &str,
+    advance: F,
+) -> Result<(i32, i32, i32, i32)>
+where
+    F: Fn(&str) -> Result<f64>,
+{
+    if !shrink || !hint.starts_with(pressed_keys) {
+        return Ok(full_rect);
+    }
+    let typed = advance(hint)? - advance(&hint[pressed_keys.len()..])?;
+    let typed = typed.round() as i32;
+    Ok((
+        full_rect.0 + align_shift(align, typed),
+        full_rect.1,
+        full_rect.2 - typed,
+        full_rect.3,
+    ))
+}
+
+/// Move and resize the label window of `rw` to `rect` in the format (x, y, w, h).
+pub fn move_render_window(
+    conn: &xcb::Connection,
+    rw: &mut RenderWindow,
+    rect: (i32, i32, i32, i32),
+) -> Result<()> {
+    if rect == rw.rect {
+        return Ok(());
+    }
+    xcb::configure_window(
+        conn,
+        rw.xcb_window_id,
+        &[
+            (xcb::CONFIG_WINDOW_X as u16, rect.0 as u32),
+            (xcb::CONFIG_WINDOW_Y as u16, rect.1 as u32),
+            (xcb::CONFIG_WINDOW_WIDTH as u16, rect.2 as u32),
+            (xcb::CONFIG_WINDOW_HEIGHT as u16, rect.3 as u32),
+        ],
+    );
+    cairo::XCBSurface::try_from(rw.cairo_context.target())
+        .ok()
+        .context("Label isn't drawn onto an XCB surface")?
+        .set_size(rect.2, rect.3)
+        .context("Couldn't resize Cairo surface")?;
+    rw.rect = rect;
+    Ok(())
+}
+
+/// Widest a label in `font` may get so that its box still fits into `width` pixels.
+pub fn max_label_width(app_config: &AppConfig, font: &FontConfig, width: i32) -> f64 {
+    let width = f64::from(width);
+    if app_config.fill {
+        return width;
+    }
+    match &app_config.padding {
+        Some(padding) => width - 2.0 * padding.x.to_pixels(font.pixel_size()),
+        None => width / (1.0 + f64::from(app_config.margin)),
+    }
+}
+
+/// Size of the box around a label in `font` with `text_extents` including margin or padding.
+pub fn box_size(
+    app_config: &AppConfig,
+    font: &FontConfig,
+    text_extents: &cairo::TextExtents,
+) -> (f64, f64) {
+    let (width, height) = match &app_config.padding {
+        Some(padding) => {
+            let font_size = font.pixel_size();
+            (
+                text_extents.width + 2.0 * padding.x.to_pixels(font_size),
+                text_extents.height + 2.0 * padding.y.to_pixels(font_size),
+            )
+        }
+        None => {
+            let margin_factor = 1.0 + f64::from(app_config.margin);
+            (
+                text_extents.width * margin_factor,
+                text_extents.height * margin_factor,
+            )
+        }
+    };
+    (
+        width.max(f64::from(app_config.min_size.width)),
+        height.max(f64::from(app_config.min_size.height)),
+    )
+}
+
+/// Add a rectangle `rect` in the format (x, y, w, h) with corners rounded by `radius` to the path
+/// of `cr`.
+fn rounded_rectangle(cr: &cairo::Context, rect: (f64, f64, f64, f64), radius: f64) {
+    let (x, y, w, h) = rect;
+    let r = radius.min(w / 2.0).min(h / 2.0);
+    cr.new_path();
+    cr.arc(x + w - r, y + r, r, -FRAC_PI_2, 0.0);
+    cr.arc(x + w - r, y + h - r, r, 0.0, FRAC_PI_2);
+    cr.arc(x + r, y + h - r, r, FRAC_PI_2, PI);
+    cr.arc(x + r, y + r, r, PI, PI + FRAC_PI_2);
+    cr.close_path();
 }
 
 /// Draw a `text` onto `rw`. In case any `current_hints` are already typed, it will draw those in a
@@ -75,83 +895,161 @@ pub fn draw_hint_text(
     text: &str,
     current_hints: &str,
 ) -> Result<()> {
-    // Paint background.
-    rw.cairo_context.set_operator(cairo::Operator::Source);
-
-    if rw.desktop_window.is_focused {
-        rw.cairo_context.set_source_rgb(
-            app_config.bg_color_current.0,
-            app_config.bg_color_current.1,
-            app_config.bg_color_current.2,
-        );
+    let (bg_color, border_color) = if rw.desktop_window.is_focused {
+        (app_config.bg_color_current, app_config.border_color_current)
     } else {
-        rw.cairo_context.set_source_rgb(
-            app_config.bg_color.0,
-            app_config.bg_color.1,
-            app_config.bg_color.2,
-        );
-    }
-    rw.cairo_context.paint().context("Error trying to draw")?;
-    rw.cairo_context.set_operator(cairo::Operator::Over);
+        (app_config.bg_color, app_config.border_color)
+    };
 
-    rw.cairo_context.select_font_face(
-        &app_config.font.font_family,
-        cairo::FontSlant::Normal,
-        cairo::FontWeight::Normal,
-    );
-    rw.cairo_context.set_font_size(app_config.font.font_size);
-    rw.cairo_context.move_to(rw.draw_pos.0, rw.draw_pos.1);
-    if text.starts_with(current_hints) {
-        // Paint already selected chars.
-        if rw.desktop_window.is_focused {
-            rw.cairo_context.set_source_rgba(
-                app_config.text_color_current_alt.0,
-                app_config.text_color_current_alt.1,
-                app_config.text_color_current_alt.2,
-                app_config.text_color_current_alt.3,
+    // The window might be larger than the box to make room for the shadow.
+    let shadow = f64::from(app_config.shadow);
+    let box_size = (f64::from(rw.rect.2) - shadow, f64::from(rw.rect.3) - shadow);
+
+    // Paint background.
+    rw.cairo_context.set_operator(cairo::Operator::Source);
+    if app_config.transparent_labels() {
+        rw.cairo_context.set_source_rgba(0.0, 0.0, 0.0, 0.0);
+        rw.cairo_context.paint().context("Error trying to draw")?;
+        if app_config.shadow > 0 {
+            rounded_rectangle(
+                &rw.cairo_context,
+                (shadow, shadow, box_size.0, box_size.1),
+                app_config.radius,
             );
-        } else {
             rw.cairo_context.set_source_rgba(
-                app_config.text_color_alt.0,
-                app_config.text_color_alt.1,
-                app_config.text_color_alt.2,
-                app_config.text_color_alt.3,
+                app_config.shadow_color.0,
+                app_config.shadow_color.1,
+                app_config.shadow_color.2,
+                app_config.shadow_color.3,
             );
+            rw.cairo_context.fill().context("Error trying to draw")?;
         }
-        for c in current_hints.chars() {
-            rw.cairo_context
-                .show_text(&c.to_string())
-                .context("Couldn't display text")?;
-        }
+        rounded_rectangle(
+            &rw.cairo_context,
+            (0.0, 0.0, box_size.0, box_size.1),
+            app_config.radius,
+        );
+        rw.cairo_context
+            .set_source_rgba(bg_color.0, bg_color.1, bg_color.2, bg_color.3);
+        rw.cairo_context.fill().context("Error trying to draw")?;
+    } else {
+        rw.cairo_context
+            .set_source_rgb(bg_color.0, bg_color.1, bg_color.2);
+        rw.cairo_context.paint().context("Error trying to draw")?;
     }
+    rw.cairo_context.set_operator(cairo::Operator::Over);
 
-    // Paint unselected chars.
-    if rw.desktop_window.is_focused {
-        rw.cairo_context.set_source_rgba(
-            app_config.text_color_current.0,
-            app_config.text_color_current.1,
-            app_config.text_color_current.2,
-            app_config.text_color_current.3,
+    // The border is drawn inside of the box.
+    if app_config.border_width > 0.0 {
+        let inset = app_config.border_width / 2.0;
+        rounded_rectangle(
+            &rw.cairo_context,
+            (
+                inset,
+                inset,
+                box_size.0 - app_config.border_width,
+                box_size.1 - app_config.border_width,
+            ),
+            (app_config.radius - inset).max(0.0),
         );
-    } else {
+        rw.cairo_context.set_line_width(app_config.border_width);
         rw.cairo_context.set_source_rgba(
-            app_config.text_color.0,
-            app_config.text_color.1,
-            app_config.text_color.2,
-            app_config.text_color.3,
+            border_color.0,
+            border_color.1,
+            border_color.2,
+            border_color.3,
         );
+        rw.cairo_context.stroke().context("Error trying to draw")?;
     }
-    let re = Regex::new(&format!("^{}", current_hints)).unwrap();
-    for c in re.replace(text, "").chars() {
+
+    if let Some((icon, rect)) = &rw.icon {
         rw.cairo_context
-            .show_text(&c.to_string())
-            .context("Couldn't show text")?;
+            .save()
+            .context("Couldn't save Cairo state")?;
+        rw.cairo_context.translate(rect.0, rect.1);
+        rw.cairo_context.scale(
+            rect.2 / f64::from(icon.width()),
+            rect.3 / f64::from(icon.height()),
+        );
+        rw.cairo_context
+            .set_source_surface(icon, 0.0, 0.0)
+            .context("Couldn't use icon as source")?;
+        rw.cairo_context.paint().context("Couldn't draw icon")?;
+        rw.cairo_context
+            .restore()
+            .context("Couldn't restore Cairo state")?;
     }
+
+    // Shrunk labels leave out the characters that were already typed.
+    let (text, current_hints) = match text.strip_prefix(current_hints) {
+        Some(remaining) if app_config.shrink => (remaining, ""),
+        _ => (text, current_hints),
+    };
+    rw.cairo_context.move_to(rw.draw_pos.0, rw.draw_pos.1);
+    let layout = create_label_layout(
+        app_config,
+        &rw.fonts,
+        &rw.label,
+        text,
+        current_hints,
+        rw.desktop_window.is_focused,
+    )?;
+    pangocairo::show_layout(&rw.cairo_context, &layout);
     rw.cairo_context.target().flush();
 
     Ok(())
 }
 
+/// Draw the prompt onto `qw`: the current `action` unless it's the default one and the search
+/// `query` while searching.
+pub fn draw_prompt(
+    qw: &QueryWindow,
+    app_config: &AppConfig,
+    action: Action,
+    query: Option<&str>,
+) -> Result<()> {
+    qw.cairo_context.set_operator(cairo::Operator::Source);
+    qw.cairo_context.set_source_rgb(
+        app_config.bg_color.0,
+        app_config.bg_color.1,
+        app_config.bg_color.2,
+    );
+    qw.cairo_context.paint().context("Error trying to draw")?;
+    qw.cairo_context.set_operator(cairo::Operator::Over);
+
+    qw.cairo_context.move_to(qw.draw_pos.0, qw.draw_pos.1);
+
+    // The action and the search prompt are drawn in the alternate color to set them apart from
+    // the query.
+    let mut text = String::new();
+    if action != Action::Focus {
+        text.push_str(action.name());
+        text.push(' ');
+    }
+    if query.is_some() {
+        text.push('/');
+    }
+    let prompt_len = text.len();
+    text.push_str(query.unwrap_or_default());
+    let attrs = pango::AttrList::new();
+    for attr in color_attributes(app_config.text_color_alt, 0, prompt_len)
+        .into_iter()
+        .chain(color_attributes(
+            app_config.text_color,
+            prompt_len,
+            text.len(),
+        ))
+    {
+        attrs.insert(attr);
+    }
+    let layout = create_layout(&qw.font, &text)?;
+    layout.set_attributes(Some(&attrs));
+    pangocairo::show_layout(&qw.cairo_context, &layout);
+    qw.cairo_context.target().flush();
+
+    Ok(())
+}
+
 /// Try to grab the keyboard until `timeout` is reached.
 ///
 /// Generally with X, I found that you can't grab global keyboard input without it failing
@@ -217,6 +1115,14 @@ pub fn snatch_mouse(conn: &xcb::Connection, screen: &xcb::Screen, timeout: Durat
     }
 }
 
+/// Current position of the mouse pointer on `screen`.
+pub fn get_pointer_position(conn: &xcb::Connection, screen: &xcb::Screen) -> Result<(i32, i32)> {
+    let reply = xcb::query_pointer(conn, screen.root())
+        .get_reply()
+        .context("Couldn't query pointer")?;
+    Ok((i32::from(reply.root_x()), i32::from(reply.root_y())))
+}
+
 /// Sort list of `DesktopWindow`s by position.
 ///
 /// This sorts by column first and row second.
@@ -227,7 +1133,7 @@ pub fn sort_by_pos(mut dws: Vec<DesktopWindow>) -> Vec<DesktopWindow> {
 }
 
 /// Returns true if `r1` and `r2` overlap.
-fn intersects(r1: (i32, i32, i32, i32), r2: (i32, i32, i32, i32)) -> bool {
+pub fn intersects(r1: (i32, i32, i32, i32), r2: (i32, i32, i32, i32)) -> bool {
     let left_corner_inside = r1.0 < r2.0 + r2.2;
     let right_corner_inside = r1.0 + r1.2 > r2.0;
     let top_corner_inside = r1.1 < r2.1 + r2.3;
@@ -235,18 +1141,69 @@ fn intersects(r1: (i32, i32, i32, i32), r2: (i32, i32, i32, i32)) -> bool {
     left_corner_inside && right_corner_inside && top_corner_inside && bottom_corner_inside
 }
 
-/// Finds overlaps and returns a list of those rects in the format (x, y, w, h).
-pub fn find_overlaps(
-    rws: Vec<&RenderWindow>,
-    rect: (i32, i32, i32, i32),
-) -> Vec<(i32, i32, i32, i32)> {
-    let mut overlaps = vec![];
-    for rw in rws {
-        if intersects(rw.rect, rect) {
-            overlaps.push(rw.rect);
+/// Returns true if `point` lies inside of `rect`.
+pub fn contains(rect: (i32, i32, i32, i32), point: (i32, i32)) -> bool {
+    (rect.0..rect.0 + rect.2).contains(&point.0) && (rect.1..rect.1 + rect.3).contains(&point.1)
+}
+
+/// Find the hint of the label at `point` or otherwise of the smallest window containing it, so
+/// floating windows can be picked over the ones they cover. Windows with hidden labels are skipped.
+pub fn find_hint_at<'a>(
+    render_windows: &'a HashMap<String, RenderWindow>,
+    point: (i32, i32),
+) -> Option<&'a String> {
+    let visible = || render_windows.iter().filter(|(_, rw)| rw.visible);
+    visible()
+        .find(|(_, rw)| contains(rw.rect, point))
+        .or_else(|| {
+            visible()
+                .filter(|(_, rw)| contains(rw.desktop_window.rect(), point))
+                .min_by_key(|(_, rw)| rw.desktop_window.size.0 * rw.desktop_window.size.1)
+        })
+        .map(|(hint, _)| hint)
+}
+
+/// Fuzzy match `query` against `text` ignoring case.
+///
+/// All characters of `query` have to appear in `text` in the same order, though not necessarily
+/// next to each other. Returns `None` if `text` doesn't match, otherwise a score where lower is
+/// better (the length of the shortest part of `text` containing the whole match).
+pub fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
+    let query: Vec<char> = query.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    let mut best = None;
+    for start in 0..text.len() {
+        if text[start] != query[0] {
+            continue;
+        }
+        let mut matched = 1;
+        let mut end = start + 1;
+        while matched < query.len() && end < text.len() {
+            if text[end] == query[matched] {
+                matched += 1;
+            }
+            end += 1;
+        }
+        if matched == query.len() {
+            match best {
+                Some(b) if b <= end - start => {}
+                _ => best = Some(end - start),
+            }
         }
     }
-    overlaps
+    best
+}
+
+/// Fuzzy match `query` against the title and class of `dw` and return the better score.
+pub fn window_score(dw: &DesktopWindow, query: &str) -> Option<usize> {
+    [&dw.title, &dw.class]
+        .iter()
+        .filter_map(|text| fuzzy_score(query, text))
+        .min()
 }
 
 /// Remove last pressed key from pressed keys
@@ -256,20 +1213,146 @@ pub fn remove_last_key(pressed_keys: &mut String, kstr: &str) {
     }
 }
 
-pub fn get_pressed_symbol(conn: &xcb::Connection, event: &xcb::base::GenericEvent) -> u32 {
-    let key_press: &xcb::KeyPressEvent = unsafe { xcb::cast_event(event) };
-    let syms = xcb_util::keysyms::KeySymbols::new(conn);
-    syms.press_lookup_keysym(key_press, 0)
+extern "C" {
+    // Not wrapped by the xkbcommon crate.
+    fn xkb_keymap_key_by_name(
+        keymap: *mut xkb::ffi::xkb_keymap,
+        name: *const std::os::raw::c_char,
+    ) -> xkb::Keycode;
+}
+
+/// A key as seen by the active keyboard layout.
+#[derive(Debug)]
+pub struct Key {
+    pub keycode: xkb::Keycode,
+    /// Keysym of the key without any modifiers in the first layout, used for key bindings.
+    pub keysym: xkb::Keysym,
+    /// Name of `keysym` as used in key sequences, eg `Control_L` or `g`.
+    pub name: String,
+    /// Text the key types, empty for keys like Return or when Control, Alt or Super are held.
+    pub text: String,
+}
+
+/// Keyboard state which translates key events using the keymap of the X server, so shifted
+/// characters, AltGr and non-Latin layouts work.
+pub struct Keyboard {
+    keymap: xkb::Keymap,
+    state: xkb::State,
 }
 
-pub fn convert_to_string<'a>(symbol: u32) -> Result<&'a str> {
-    unsafe {
-        CStr::from_ptr(x11::xlib::XKeysymToString(symbol.into()))
-            .to_str()
-            .context("Couldn't create Rust string from C string")
+impl Keyboard {
+    pub fn new(conn: &xcb::Connection) -> Result<Keyboard> {
+        let raw_conn = conn.get_raw_conn() as *mut _;
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = unsafe {
+            let (mut major, mut minor, mut base_event, mut base_error) = (0, 0, 0, 0);
+            if xkb::x11::ffi::xkb_x11_setup_xkb_extension(
+                raw_conn,
+                xkb::x11::MIN_MAJOR_XKB_VERSION,
+                xkb::x11::MIN_MINOR_XKB_VERSION,
+                xkb::x11::ffi::xkb_x11_setup_xkb_extension_flags::NO_FLAGS,
+                &mut major,
+                &mut minor,
+                &mut base_event,
+                &mut base_error,
+            ) == 0
+            {
+                bail!("X server doesn't support the XKB extension");
+            }
+            let device_id = xkb::x11::ffi::xkb_x11_get_core_keyboard_device_id(raw_conn);
+            if device_id == -1 {
+                bail!("Couldn't find core keyboard device");
+            }
+            let keymap = xkb::x11::ffi::xkb_x11_keymap_new_from_device(
+                context.get_raw_ptr(),
+                raw_conn,
+                device_id,
+                xkb::KEYMAP_COMPILE_NO_FLAGS,
+            );
+            if keymap.is_null() {
+                bail!("Couldn't get keymap from X server");
+            }
+            xkb::Keymap::from_raw_ptr(keymap)
+        };
+        let state = xkb::State::new(&keymap);
+        Ok(Keyboard { keymap, state })
+    }
+
+    /// Look up the key of a key press or release `event`.
+    pub fn lookup(&mut self, event: &xcb::base::GenericEvent) -> Key {
+        let key_event: &xcb::KeyPressEvent = unsafe { xcb::cast_event(event) };
+        let keycode = xkb::Keycode::from(key_event.detail());
+
+        // The event carries the modifiers in its lower byte and the layout group in bits 13 and 14.
+        let mods = u32::from(key_event.state());
+        self.state
+            .update_mask(mods & 0xff, 0, 0, 0, 0, (mods >> 13) & 0x3);
+
+        let keysym = self
+            .keymap
+            .key_get_syms_by_level(keycode, 0, 0)
+            .first()
+            .copied()
+            .unwrap_or(xkb::KEY_NoSymbol);
+        let bound = [xkb::MOD_NAME_CTRL, xkb::MOD_NAME_ALT, xkb::MOD_NAME_LOGO]
+            .iter()
+            .any(|name| {
+                self.state
+                    .mod_name_is_active(name, xkb::STATE_MODS_EFFECTIVE)
+            });
+        let text = self.state.key_get_utf8(keycode);
+        let text = if bound || text.chars().any(char::is_control) {
+            String::new()
+        } else {
+            text
+        };
+        Key {
+            keycode,
+            keysym,
+            name: xkb::keysym_get_name(keysym),
+            text,
+        }
+    }
+
+    /// Look up the key called `name` (eg `AC01`) and the character it types without modifiers in
+    /// the first layout.
+    pub fn key_by_name(&self, name: &str) -> Option<(xkb::Keycode, char)> {
+        let c_name = CString::new(name).ok()?;
+        let keycode = unsafe { xkb_keymap_key_by_name(self.keymap.get_raw_ptr(), c_name.as_ptr()) };
+        if keycode == xkb::KEYCODE_INVALID {
+            return None;
+        }
+        let keysym = *self.keymap.key_get_syms_by_level(keycode, 0, 0).first()?;
+        char::from_u32(xkb::keysym_to_utf32(keysym))
+            .filter(|c| *c != '\0' && !c.is_control())
+            .map(|c| (keycode, c))
+    }
+
+    /// Map the physical keys called `names` to the characters they type, so they can be used as
+    /// hint characters.
+    pub fn hint_keys(&self, names: &[String]) -> Result<Vec<(xkb::Keycode, char)>> {
+        let mut keys: Vec<(xkb::Keycode, char)> = vec![];
+        for name in names {
+            let (keycode, c) = self
+                .key_by_name(name)
+                .with_context(|| format!("Key '{}' doesn't type a character", name))?;
+            if keys.iter().any(|(_, other)| *other == c) {
+                bail!("Key '{}' types '{}' which is already used", name, c);
+            }
+            keys.push((keycode, c));
+        }
+        Ok(keys)
     }
 }
 
+/// Whether `keysym` belongs to a modifier key such as Shift or AltGr.
+pub fn is_modifier(keysym: xkb::Keysym) -> bool {
+    (xkb::KEY_Shift_L..=xkb::KEY_Hyper_R).contains(&keysym)
+        || (xkb::KEY_ISO_Lock..=xkb::KEY_ISO_Level5_Lock).contains(&keysym)
+        || keysym == xkb::KEY_Mode_switch
+        || keysym == xkb::KEY_Num_Lock
+}
+
 /// Struct helps to write sequence and check if it is found in list of exit sequences
 #[derive(Debug, PartialEq)]
 pub struct Sequence {
@@ -330,6 +1413,112 @@ mod tests {
         assert!(!intersects((1905, 705, 31, 82), (2000, 723, 38, 64)));
     }
 
+    #[test]
+    fn test_contains() {
+        assert!(contains((10, 10, 20, 20), (10, 29)));
+        assert!(!contains((10, 10, 20, 20), (30, 15)));
+        assert!(!contains((10, 10, 20, 20), (15, 9)));
+    }
+
+    #[test]
+    fn test_get_next_hint_non_ascii() {
+        // Two characters with two bytes each aren't enough for three windows.
+        let first = get_next_hint(vec![], "éö", 3).unwrap();
+        assert_eq!(first.chars().count(), 2);
+        let second = get_next_hint(vec![&first], "éö", 3).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_assign_hints() {
+        let hints = assign_hints(&[1, 2, 3], "asdf", &HashMap::new()).unwrap();
+        assert_eq!(hints, ["a", "s", "d"]);
+
+        // Window 2 keeps its hint while the hint of window 3 isn't made of the hint chars.
+        let previous = [(2, "a"), (3, "x"), (4, "s")]
+            .iter()
+            .map(|(id, hint)| (*id, hint.to_string()))
+            .collect();
+        let hints = assign_hints(&[1, 2, 3], "asdf", &previous).unwrap();
+        assert_eq!(hints, ["s", "a", "d"]);
+
+        // With more windows, hints need two characters and the old ones don't fit anymore.
+        let hints = assign_hints(&[1, 2, 3, 4, 5], "asdf", &previous).unwrap();
+        assert!(hints.iter().all(|hint| hint.len() == 2));
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert_eq!(fuzzy_score("", "Firefox"), Some(0));
+        assert_eq!(fuzzy_score("fire", "Firefox"), Some(4));
+        assert_eq!(fuzzy_score("ffx", "Firefox"), Some(7));
+        assert_eq!(fuzzy_score("FOX", "firefox"), Some(3));
+        assert_eq!(fuzzy_score("xf", "Firefox"), None);
+        assert_eq!(fuzzy_score("term", "Alacritty"), None);
+    }
+
+    #[test]
+    fn test_fuzzy_score_prefers_tight_match() {
+        // Both "v...im" and the later contiguous "vim" match, the latter should win.
+        assert_eq!(fuzzy_score("vim", "very important vim"), Some(3));
+    }
+
+    #[test]
+    fn test_pick_icon() {
+        // Icons of 2x2, 1x3 and 3x2 pixels.
+        let data = [2, 2, 1, 1, 1, 1, 1, 3, 2, 2, 2, 3, 2, 3, 3, 3, 3, 3, 3];
+        assert_eq!(pick_icon(&data, 1), Some((1, 3, &data[8..11])));
+        assert_eq!(pick_icon(&data, 2), Some((2, 2, &data[2..6])));
+        assert_eq!(pick_icon(&data, 3), Some((3, 2, &data[13..19])));
+        assert_eq!(pick_icon(&[], 3), None);
+
+        // Truncated icons are ignored.
+        assert_eq!(pick_icon(&data[..15], 3), Some((2, 2, &data[2..6])));
+    }
+
+    #[test]
+    fn test_expand_label() {
+        let dw = DesktopWindow {
+            id: 1,
+            x_window_id: None,
+            pos: (0, 0),
+            size: (100, 100),
+            is_focused: false,
+            title: "~/src".to_string(),
+            class: "Alacritty".to_string(),
+            output: None,
+            titlebar: None,
+            content: None,
+        };
+        let template = [
+            LabelPart::Hint,
+            LabelPart::Text(" ".to_string()),
+            LabelPart::Class,
+            LabelPart::Text(": ".to_string()),
+            LabelPart::Title,
+        ];
+        assert_eq!(
+            expand_label(&template, &dw),
+            vec![
+                LabelSegment::Hint,
+                LabelSegment::Meta(" Alacritty: ~/src".to_string())
+            ]
+        );
+        assert_eq!(
+            expand_label(&[LabelPart::Hint], &dw),
+            vec![LabelSegment::Hint]
+        );
+    }
+
+    #[test]
+    fn test_ellipsize() {
+        let measure = |t: &str| t.chars().count() as f64;
+        assert_eq!(ellipsize("Firefox", 7.0, measure), "Firefox");
+        assert_eq!(ellipsize("Firefox", 5.0, measure), "Fire…");
+        assert_eq!(ellipsize("New tab", 5.0, measure), "New…");
+        assert_eq!(ellipsize("Firefox", 0.0, measure), "");
+    }
+
     #[test]
     fn test_sequences_equal() {
         let a = Sequence::new(Some("Control_L+Shift_L+a"));
@@ -376,4 +1565,38 @@ mod tests {
 
         assert!(!sequence.is_started());
     }
+
+    #[test]
+    fn test_label_rect() {
+        let full_rect = (100, 50, 40, 20);
+        let advance = |text: &str| Ok(10.0 * text.len() as f64);
+        let rect = |hint, pressed_keys| {
+            label_rect(
+                full_rect,
+                HorizontalAlign::Right,
+                true,
+                hint,
+                pressed_keys,
+                advance,
+            )
+            .unwrap()
+        };
+
+        // All of aaa, aab and aba match at first and shrink, then aba stops matching and has to
+        // be as large as before to fit its whole hint again.
+        assert_eq!(rect("aba", "a"), (110, 50, 30, 20));
+        assert_eq!(rect("aab", "aa"), (120, 50, 20, 20));
+        assert_eq!(rect("aba", "aa"), full_rect);
+        assert_eq!(rect("aba", ""), full_rect);
+
+        let rect = label_rect(
+            full_rect,
+            HorizontalAlign::Left,
+            false,
+            "aab",
+            "aa",
+            advance,
+        );
+        assert_eq!(rect.unwrap(), full_rect);
+    }
 }
@@ -1,9 +1,12 @@
 use anyhow::{Context, Result};
-use i3ipc::reply::{Node, NodeLayout, NodeType, Workspace};
-use i3ipc::I3Connection;
-use log::{debug, info};
+use i3ipc::reply::{Node, NodeLayout, NodeType, WindowProperty, Workspace};
+use i3ipc::{I3Connection, I3EventListener, Subscription};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
 
-use crate::DesktopWindow;
+use crate::{DesktopWindow, SharedWindowCache};
 
 /// Find first `Node` that fulfills a given criterion.
 fn find_first_node_with_attr<F>(start_node: &Node, predicate: F) -> Option<&Node>
@@ -24,20 +27,196 @@ where
     None
 }
 
-/// Find parent of `child`.
-fn find_parent_of<'a>(start_node: &'a Node, child: &'a Node) -> Option<&'a Node> {
-    let mut nodes_to_explore: Vec<&Node> = start_node.nodes.iter().collect();
-    while !nodes_to_explore.is_empty() {
-        let mut next_vec = vec![];
-        for node in &nodes_to_explore {
-            if node.nodes.iter().any(|x| child.id == x.id) {
-                return Some(node);
-            }
-            next_vec.extend(node.nodes.iter());
+/// A rect in the format (x, y, w, h).
+type Rect = (i32, i32, i32, i32);
+
+/// How a container arranges its children, as far as placing hints is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    Split,
+    Tabbed,
+    Stacked,
+}
+
+/// The parts of an i3 container needed to find its windows and where they are.
+#[derive(Debug)]
+struct Con {
+    id: i64,
+    name: Option<String>,
+    class: Option<String>,
+    window: Option<i32>,
+    layout: Layout,
+    rect: Rect,
+    deco_rect: Rect,
+    /// Ids of the children, most recently focused first.
+    focus: Vec<i64>,
+    focused: bool,
+    nodes: Vec<Con>,
+    floating_nodes: Vec<Con>,
+}
+
+impl From<&Node> for Con {
+    fn from(node: &Node) -> Con {
+        Con {
+            id: node.id,
+            name: node.name.clone(),
+            class: node
+                .window_properties
+                .as_ref()
+                .and_then(|props| props.get(&WindowProperty::Class))
+                .cloned(),
+            window: node.window,
+            layout: match node.layout {
+                NodeLayout::Tabbed => Layout::Tabbed,
+                NodeLayout::Stacked => Layout::Stacked,
+                _ => Layout::Split,
+            },
+            rect: node.rect,
+            deco_rect: node.deco_rect,
+            focus: node.focus.clone(),
+            focused: node.focused,
+            nodes: node.nodes.iter().map(Con::from).collect(),
+            floating_nodes: node.floating_nodes.iter().map(Con::from).collect(),
         }
-        nodes_to_explore = next_vec;
     }
-    None
+}
+
+/// How a container is shown on screen, handed down while crawling the tree.
+#[derive(Debug, Clone, Copy)]
+struct Visibility {
+    /// Whether the content of the container is displayed.
+    shown: bool,
+    /// Title bar or tab of the container. For hidden containers, this is the tab of the outermost
+    /// hidden container instead, as that's what has to be clicked to show them.
+    header: Option<Rect>,
+}
+
+/// Title bar or tab of `con` as child of `parent`, if it has one.
+fn header_of(con: &Con, parent: &Con) -> Option<Rect> {
+    if con.deco_rect.3 <= 0 {
+        return None;
+    }
+    // Tabs and stacked title bars are positioned relative to their parent.
+    let header = match parent.layout {
+        Layout::Tabbed | Layout::Stacked => (
+            parent.rect.0 + con.deco_rect.0,
+            parent.rect.1 + con.deco_rect.1,
+            con.deco_rect.2,
+            con.deco_rect.3,
+        ),
+        Layout::Split => (
+            con.rect.0,
+            con.rect.1 - con.deco_rect.3,
+            con.rect.2,
+            con.deco_rect.3,
+        ),
+    };
+    Some(header)
+}
+
+/// Visibility of `child` of `parent`, which has `visibility` itself. Only the most recently focused
+/// tiled child of a tabbed or stacked container is shown, while floating children always are.
+fn child_visibility(
+    child: &Con,
+    parent: &Con,
+    visibility: Visibility,
+    floating: bool,
+) -> Visibility {
+    if !visibility.shown {
+        return visibility;
+    }
+    // The focus list of workspaces contains their floating containers as well.
+    let visible_tab = parent
+        .focus
+        .iter()
+        .find(|id| parent.nodes.iter().any(|node| node.id == **id));
+    Visibility {
+        shown: floating || parent.layout == Layout::Split || visible_tab == Some(&child.id),
+        header: header_of(child, parent),
+    }
+}
+
+/// Create the `DesktopWindow` for the window `con` with `visibility` as child of `parent`.
+///
+/// Shown windows span from their title bar to the bottom of their content, windows in tabbed
+/// containers only as wide as their tab. Hidden windows are just their tab.
+fn desktop_window(con: &Con, parent: &Con, visibility: Visibility, output: &str) -> DesktopWindow {
+    let bottom = con.rect.1 + con.rect.3;
+    let rect = match (visibility.shown, visibility.header) {
+        (true, Some(header)) if parent.layout == Layout::Tabbed => {
+            (header.0, header.1, header.2, bottom - header.1)
+        }
+        (true, Some(header)) => (con.rect.0, header.1, con.rect.2, bottom - header.1),
+        (false, Some(header)) => header,
+        (_, None) => con.rect,
+    };
+
+    DesktopWindow {
+        id: con.id,
+        x_window_id: con.window,
+        pos: (rect.0, rect.1),
+        size: (rect.2, rect.3),
+        is_focused: con.focused,
+        title: con.name.clone().unwrap_or_default(),
+        class: con.class.clone().unwrap_or_default(),
+        output: Some(output.to_string()),
+        titlebar: visibility.header,
+        content: visibility.shown.then_some(con.rect),
+    }
+}
+
+/// Collect the windows below `con`, which has `visibility` and is on `output`, into `windows`.
+///
+/// The visibility of each container is handed down to its children, so this takes a single pass
+/// over the tree no matter how deeply containers are nested.
+fn crawl_con(con: &Con, visibility: Visibility, output: &str, windows: &mut Vec<DesktopWindow>) {
+    let children = con.nodes.iter().map(|child| (child, false));
+    let floating_children = con.floating_nodes.iter().map(|child| (child, true));
+    for (child, floating) in children.chain(floating_children) {
+        let child_visibility = child_visibility(child, con, visibility, floating);
+        if child.window.is_some() {
+            let window = desktop_window(child, con, child_visibility, output);
+            debug!("Found {:?}", window);
+            windows.push(window);
+        }
+        crawl_con(child, child_visibility, output, windows);
+    }
+}
+
+/// Hidden windows nested in the same background tab are all only reachable through that tab. Split
+/// the tab between them, in the order they are in the tree, so that their labels don't end up on
+/// top of each other.
+fn split_shared_tabs(windows: &mut [DesktopWindow]) {
+    let mut shared: HashMap<Rect, Vec<usize>> = HashMap::new();
+    for (i, window) in windows.iter().enumerate() {
+        if let (None, Some(tab)) = (window.content, window.titlebar) {
+            shared.entry(tab).or_default().push(i);
+        }
+    }
+    for (tab, indices) in shared {
+        let count = indices.len() as i32;
+        for (n, i) in indices.into_iter().enumerate() {
+            let n = n as i32;
+            let x = tab.0 + tab.2 * n / count;
+            let part = (x, tab.1, tab.0 + tab.2 * (n + 1) / count - x, tab.3);
+            let window = &mut windows[i];
+            window.pos = (part.0, part.1);
+            window.size = (part.2, part.3);
+            window.titlebar = Some(part);
+        }
+    }
+}
+
+/// Return a list of all `DesktopWindow`s in the workspace container `workspace_con` on `output`.
+fn crawl_workspace(workspace_con: &Con, output: &str) -> Vec<DesktopWindow> {
+    let visibility = Visibility {
+        shown: true,
+        header: None,
+    };
+    let mut windows = vec![];
+    crawl_con(workspace_con, visibility, output, &mut windows);
+    split_shared_tabs(&mut windows);
+    windows
 }
 
 /// Return a list of all `DesktopWindow`s for the given `Workspace`.
@@ -46,52 +225,10 @@ fn crawl_windows(root_node: &Node, workspace: &Workspace) -> Result<Vec<DesktopW
         x.name == Some(workspace.name.clone()) && x.nodetype == NodeType::Workspace
     })
     .context("Couldn't find the Workspace node")?;
-
-    let mut nodes_to_explore: Vec<&Node> = workspace_node.nodes.iter().collect();
-    nodes_to_explore.extend(workspace_node.floating_nodes.iter());
-    let mut windows = vec![];
-    while !nodes_to_explore.is_empty() {
-        let mut next_vec = vec![];
-        for node in &nodes_to_explore {
-            next_vec.extend(node.nodes.iter());
-            next_vec.extend(node.floating_nodes.iter());
-            if node.window.is_some() {
-                let root_node = find_parent_of(root_node, node);
-
-                let (pos_x, size_x) = if let Some(root_node) = root_node {
-                    if root_node.layout == NodeLayout::Tabbed {
-                        (node.rect.0 + node.deco_rect.0, node.deco_rect.2)
-                    } else {
-                        (node.rect.0, node.rect.2)
-                    }
-                } else {
-                    (node.rect.0, node.rect.2)
-                };
-
-                let pos_y = if let Some(root_node) = root_node {
-                    if root_node.layout == NodeLayout::Stacked {
-                        root_node.rect.1 + node.deco_rect.1
-                    } else {
-                        node.rect.1 - node.deco_rect.3
-                    }
-                } else {
-                    node.rect.1 - node.deco_rect.3
-                };
-
-                let window = DesktopWindow {
-                    id: node.id,
-                    x_window_id: node.window,
-                    pos: (pos_x, pos_y),
-                    size: (size_x, (node.rect.3 + node.deco_rect.3)),
-                    is_focused: node.focused,
-                };
-                debug!("Found {:?}", window);
-                windows.push(window);
-            }
-        }
-        nodes_to_explore = next_vec;
-    }
-    Ok(windows)
+    Ok(crawl_workspace(
+        &Con::from(workspace_node),
+        &workspace.output,
+    ))
 }
 
 /// Return a list of all windows.
@@ -111,13 +248,185 @@ pub fn get_windows() -> Result<Vec<DesktopWindow>> {
     Ok(windows)
 }
 
-/// Focus a specific `window`.
-pub fn focus_window(window: &DesktopWindow) -> Result<()> {
+/// Collect the windows on all visible workspaces again into `window_cache`.
+///
+/// The cache stays locked while i3 is queried, so that concurrent refreshes can't replace a
+/// newer list of windows with an older one.
+pub fn refresh_windows(window_cache: &SharedWindowCache) -> Result<()> {
+    let mut cache = window_cache.lock().unwrap();
+    let desktop_windows = get_windows()?;
+    cache.update(desktop_windows);
+    Ok(())
+}
+
+/// Collect the windows again on every event that might have changed them.
+fn listen_for_changes(window_cache: &SharedWindowCache) -> Result<()> {
+    let mut listener = I3EventListener::connect().context("Couldn't acquire i3 connection")?;
+    listener
+        .subscribe(&[
+            Subscription::Window,
+            Subscription::Workspace,
+            Subscription::Output,
+        ])
+        .context("Couldn't subscribe to i3 events")?;
+
+    // Only start out after subscribing so that no changes are missed.
+    refresh_windows(window_cache)?;
+    for event in listener.listen() {
+        let event = event.context("Couldn't receive i3 event")?;
+        debug!("Received {:?}", event);
+        refresh_windows(window_cache)?;
+    }
+    Ok(())
+}
+
+/// Keep `window_cache` up to date with the windows on all visible workspaces by listening for i3
+/// events in the background.
+///
+/// While i3 can't be reached, eg during a restart, the cache is cleared and windows have to be
+/// queried directly.
+pub fn watch_windows(window_cache: SharedWindowCache) {
+    thread::spawn(move || loop {
+        if let Err(e) = listen_for_changes(&window_cache) {
+            warn!("Couldn't watch i3 for window changes: {:#}", e);
+        }
+        window_cache.lock().unwrap().clear();
+        thread::sleep(Duration::from_secs(1));
+    });
+}
+
+/// Run an i3 `command_str`.
+fn run_command(command_str: &str) -> Result<()> {
     let mut connection = I3Connection::connect().context("Couldn't acquire i3 connection")?;
-    let command_str = format!("[con_id=\"{}\"] focus", window.id);
     let command = connection
-        .run_command(&command_str)
+        .run_command(command_str)
         .context("Couldn't communicate with i3")?;
     info!("Sending to i3: {:?}", command);
     Ok(())
 }
+
+/// Focus a specific `window`.
+pub fn focus_window(window: &DesktopWindow) -> Result<()> {
+    run_command(&format!("[con_id=\"{}\"] focus", window.id))
+}
+
+/// Swap the currently focused window with `window`.
+pub fn swap_window(window: &DesktopWindow) -> Result<()> {
+    run_command(&format!("swap container with con_id {}", window.id))
+}
+
+/// Close a specific `window`.
+pub fn close_window(window: &DesktopWindow) -> Result<()> {
+    run_command(&format!("[con_id=\"{}\"] kill", window.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use i3ipc::event::WorkspaceEventInfo;
+    use std::str::FromStr;
+
+    const NESTED: &str = include_str!("../fixtures/i3/nested.json");
+
+    /// Parse a `get_tree` reply with the tree builder of i3ipc. It's only exposed for events, so
+    /// the tree is wrapped into one.
+    fn tree_from_json(fixture: &str) -> Node {
+        let event = format!(r#"{{"change": "init", "current": {}}}"#, fixture);
+        WorkspaceEventInfo::from_str(&event)
+            .unwrap()
+            .current
+            .unwrap()
+    }
+
+    /// Windows on the workspace `name` of the tree in `fixture` by their title. Container ids
+    /// differ between recordings, titles are set by `fixtures/i3/record.sh`.
+    fn windows_in(fixture: &str, name: &str) -> HashMap<String, DesktopWindow> {
+        let workspace = Workspace {
+            num: name.parse().unwrap(),
+            name: name.to_string(),
+            visible: true,
+            focused: false,
+            urgent: false,
+            rect: (0, 0, 1920, 1080),
+            output: "eDP-1".to_string(),
+        };
+        crawl_windows(&tree_from_json(fixture), &workspace)
+            .unwrap()
+            .into_iter()
+            .map(|window| (window.title.clone(), window))
+            .collect()
+    }
+
+    #[test]
+    fn test_crawl_nested_containers() {
+        let windows = windows_in(NESTED, "1");
+        assert_eq!(windows.len(), 7);
+
+        // Background tab of a tabbed container.
+        assert_eq!(windows["vim"].rect(), (0, 0, 480, 20));
+        assert_eq!(windows["vim"].content, None);
+
+        // Windows in a split container that is the visible tab have their own title bars.
+        assert_eq!(windows["Mozilla Firefox"].rect(), (0, 20, 960, 530));
+        assert_eq!(windows["Mozilla Firefox"].titlebar, Some((0, 20, 960, 20)));
+        assert_eq!(windows["Mozilla Firefox"].content, Some((0, 40, 960, 510)));
+        assert_eq!(windows["htop"].rect(), (0, 550, 960, 530));
+        assert!(windows["htop"].is_focused);
+
+        // Collapsed window of a stacked container and a tabbed container inside of it.
+        assert_eq!(windows["Signal"].rect(), (960, 0, 960, 20));
+        assert_eq!(windows["notes.md"].rect(), (960, 40, 480, 1040));
+        assert_eq!(windows["notes.md"].content, Some((960, 60, 960, 1020)));
+        assert_eq!(windows["Zathura"].rect(), (1440, 40, 480, 20));
+
+        assert_eq!(windows["pavucontrol"].rect(), (600, 300, 400, 300));
+        assert_eq!(windows["pavucontrol"].class, "Pavucontrol");
+        assert_eq!(windows["pavucontrol"].output.as_deref(), Some("eDP-1"));
+    }
+
+    #[test]
+    fn test_visible_tab_with_floating_focus() {
+        let con = |id, nodes, floating_nodes| Con {
+            id,
+            name: None,
+            class: None,
+            window: Some(id as i32),
+            layout: Layout::Tabbed,
+            rect: (0, 0, 100, 100),
+            deco_rect: (0, 0, 50, 20),
+            focus: vec![3, 2, 1],
+            focused: false,
+            nodes,
+            floating_nodes,
+        };
+        // The floating window was focused last, but the second tab is still the visible one.
+        let workspace = con(
+            0,
+            vec![con(1, vec![], vec![]), con(2, vec![], vec![])],
+            vec![con(3, vec![], vec![])],
+        );
+        let windows: HashMap<_, _> = crawl_workspace(&workspace, "eDP-1")
+            .into_iter()
+            .map(|window| (window.id, window))
+            .collect();
+        assert!(windows[&1].content.is_none());
+        assert!(windows[&2].content.is_some());
+        assert!(windows[&3].content.is_some());
+    }
+
+    #[test]
+    fn test_crawl_hidden_nested_containers() {
+        let windows = windows_in(NESTED, "2");
+        assert_eq!(windows["mutt"].rect(), (0, 0, 960, 1080));
+
+        // Windows of a split container in a background tab are only reachable through its tab,
+        // which they share.
+        assert_eq!(windows["Telegram"].rect(), (960, 0, 480, 20));
+        assert_eq!(windows["Telegram"].titlebar, Some((960, 0, 480, 20)));
+        assert_eq!(windows["mpv"].rect(), (1440, 0, 480, 20));
+        assert_eq!(windows["mpv"].titlebar, Some((1440, 0, 480, 20)));
+        for title in ["Telegram", "mpv"] {
+            assert_eq!(windows[title].content, None);
+        }
+    }
+}